@@ -0,0 +1,302 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Uri, header},
+    middleware::Next,
+    response::Response,
+};
+use http_body::{Frame, SizeHint};
+use std::net::IpAddr;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use crate::{
+    auth::AuthOutcome, bucket::BucketManager, config::AccessLogFormat, handlers::unique_id,
+    ip_filter, vhost,
+};
+
+const REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// Request IDs from clients or proxies are kept if they look like an ID,
+// anything else is replaced so it cannot forge or break log lines.
+fn valid_request_id(value: &HeaderValue) -> bool {
+    let id = value.as_bytes();
+    !id.is_empty()
+        && id.len() <= 128
+        && id
+            .iter()
+            .all(|c| c.is_ascii_alphanumeric() || b"-_.:".contains(c))
+}
+
+// Presigned URL signatures grant access just like tokens do.
+fn redact(uri: &Uri) -> String {
+    let Some(query) = uri.query() else {
+        return uri.path().to_string();
+    };
+    let query: Vec<&str> = query
+        .split('&')
+        .map(|pair| match pair.split_once('=') {
+            Some(("signature", _)) => "signature=REDACTED",
+            _ => pair,
+        })
+        .collect();
+    format!("{}?{}", uri.path(), query.join("&"))
+}
+
+struct Entry {
+    format: AccessLogFormat,
+    time: SystemTime,
+    start: Instant,
+    request_id: String,
+    client: Option<IpAddr>,
+    method: String,
+    // Only the path and query are logged; request headers other than
+    // User-Agent and Referer, Authorization in particular, never are.
+    // Presigned URL signatures are redacted.
+    uri: String,
+    version: String,
+    status: u16,
+    bucket: Option<String>,
+    auth: Option<AuthOutcome>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+}
+
+fn header_string(headers: &HeaderMap, name: HeaderName) -> Option<String> {
+    headers
+        .get(name)
+        .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+}
+
+pub async fn access_log(
+    State(manager): State<Arc<BucketManager>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let time = SystemTime::now();
+    let start = Instant::now();
+
+    let request_id = match request.headers().get(&REQUEST_ID) {
+        Some(id) if valid_request_id(id) => id.clone(),
+        _ => HeaderValue::from_str(&unique_id()).unwrap_or(HeaderValue::from_static("-")),
+    };
+    request.headers_mut().insert(REQUEST_ID, request_id.clone());
+
+    let client = ip_filter::client_ip(&manager, &request);
+    let method = request.method().to_string();
+    let uri = redact(request.uri());
+    let version = format!("{:?}", request.version());
+    let bucket = vhost::request_bucket(&manager, &request);
+    let user_agent = header_string(request.headers(), header::USER_AGENT);
+    let referer = header_string(request.headers(), header::REFERER);
+
+    let mut response = next.run(request).await;
+    response
+        .headers_mut()
+        .insert(REQUEST_ID, request_id.clone());
+
+    let entry = Entry {
+        format: manager.access_log_format(),
+        time,
+        start,
+        request_id: String::from_utf8_lossy(request_id.as_bytes()).into_owned(),
+        client,
+        method,
+        uri,
+        version,
+        status: response.status().as_u16(),
+        bucket,
+        auth: response.extensions().get::<AuthOutcome>().copied(),
+        user_agent,
+        referer,
+    };
+    response.map(|body| {
+        Body::new(LoggedBody {
+            inner: body,
+            bytes: 0,
+            entry: Some(entry),
+        })
+    })
+}
+
+// Writes the log line once the response body has been sent, or dropped
+// because the client went away, so bytes and duration cover the transfer.
+struct LoggedBody {
+    inner: Body,
+    bytes: u64,
+    entry: Option<Entry>,
+}
+
+impl http_body::Body for LoggedBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.bytes += data.len() as u64;
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for LoggedBody {
+    fn drop(&mut self) {
+        if let Some(entry) = self.entry.take() {
+            println!("{}", format_entry(&entry, self.bytes));
+        }
+    }
+}
+
+fn format_entry(entry: &Entry, bytes: u64) -> String {
+    let client = entry
+        .client
+        .map(|ip| ip.to_string())
+        .unwrap_or_else(|| "-".to_string());
+    match entry.format {
+        AccessLogFormat::Plain => format!(
+            "{} {} {} {}ms",
+            entry.method,
+            entry.uri,
+            entry.status,
+            entry.start.elapsed().as_millis()
+        ),
+        AccessLogFormat::Common => format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            client,
+            clf_time(entry.time),
+            entry.method,
+            escape(&entry.uri),
+            entry.version,
+            entry.status,
+            clf_bytes(bytes)
+        ),
+        AccessLogFormat::Combined => format!(
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\"",
+            client,
+            clf_time(entry.time),
+            entry.method,
+            escape(&entry.uri),
+            entry.version,
+            entry.status,
+            clf_bytes(bytes),
+            escape(entry.referer.as_deref().unwrap_or("-")),
+            escape(entry.user_agent.as_deref().unwrap_or("-"))
+        ),
+        AccessLogFormat::Json => serde_json::json!({
+            "time": rfc3339_time(entry.time),
+            "request_id": entry.request_id,
+            "client": entry.client.map(|ip| ip.to_string()),
+            "method": entry.method,
+            "uri": entry.uri,
+            "protocol": entry.version,
+            "status": entry.status,
+            "bytes": bytes,
+            "duration_ms": entry.start.elapsed().as_secs_f64() * 1000.0,
+            "bucket": entry.bucket,
+            "auth": entry.auth.map(AuthOutcome::as_str),
+            "user_agent": entry.user_agent,
+            "referer": entry.referer,
+        })
+        .to_string(),
+    }
+}
+
+fn clf_bytes(bytes: u64) -> String {
+    if bytes == 0 {
+        "-".to_string()
+    } else {
+        bytes.to_string()
+    }
+}
+
+// Quotes and control characters would let a client inject fake fields or
+// lines into the quoted parts of the common/combined formats.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            c if c.is_control() => escaped.push_str(&format!("\\x{:02x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+struct UtcTime {
+    year: i64,
+    month: u32,
+    day: u32,
+    hour: u64,
+    minute: u64,
+    second: u64,
+    millis: u32,
+}
+
+// Days since 1970-01-01 to a proleptic Gregorian date, after Howard
+// Hinnant's `civil_from_days`.
+fn utc(time: SystemTime) -> UtcTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let days = (secs / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    UtcTime {
+        year,
+        month,
+        day,
+        hour: secs % 86400 / 3600,
+        minute: secs % 3600 / 60,
+        second: secs % 60,
+        millis: since_epoch.subsec_millis(),
+    }
+}
+
+// e.g. `10/Oct/2000:13:55:36 +0000`
+fn clf_time(time: SystemTime) -> String {
+    const MONTHS: [&str; 12] = [
+        "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+    ];
+    let t = utc(time);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        t.day,
+        MONTHS[t.month as usize - 1],
+        t.year,
+        t.hour,
+        t.minute,
+        t.second
+    )
+}
+
+// e.g. `2000-10-10T13:55:36.123Z`
+fn rfc3339_time(time: SystemTime) -> String {
+    let t = utc(time);
+    format!(
+        "{}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        t.year, t.month, t.day, t.hour, t.minute, t.second, t.millis
+    )
+}
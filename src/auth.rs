@@ -1,8 +1,390 @@
-use axum::http::HeaderMap;
+use argon2::{
+    Argon2, PasswordHasher, PasswordVerifier,
+    password_hash::{PasswordHash, SaltString, rand_core::OsRng},
+};
+use axum::{
+    http::{HeaderMap, HeaderValue, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use base64::{Engine, prelude::BASE64_STANDARD};
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
 
-pub fn extract_token(headers: &HeaderMap) -> Option<&str> {
-    headers
-        .get("authorization")
-        .and_then(|v| v.to_str().ok())
-        .and_then(|v| v.strip_prefix("Bearer "))
+use crate::bucket::Bucket;
+use crate::config::{BucketAuth, Scope};
+use crate::jwt::JwtVerifier;
+
+// The token from `Authorization: Bearer <token>`, or the password of HTTP
+// Basic credentials so browsers can log in to private buckets. The Basic
+// user name is ignored.
+pub fn extract_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    if let Some(token) = value.strip_prefix("Bearer ") {
+        return Some(token.to_string());
+    }
+    let credentials = BASE64_STANDARD
+        .decode(value.strip_prefix("Basic ")?.trim())
+        .ok()?;
+    let credentials = String::from_utf8(credentials).ok()?;
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+// Result of checking a request's token. Handlers attach it to their response
+// as an extension so the access log can report it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AuthOutcome {
+    Granted,
+    Missing,
+    Invalid,
+    Denied,
+}
+
+impl AuthOutcome {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            AuthOutcome::Granted => "granted",
+            AuthOutcome::Missing => "missing",
+            AuthOutcome::Invalid => "invalid",
+            AuthOutcome::Denied => "denied",
+        }
+    }
+}
+
+// Why a request was refused: no token (401), an unknown token, or one that
+// lacks the scope or path (both 403).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejection {
+    Missing,
+    Invalid,
+    Denied,
+}
+
+impl Rejection {
+    fn outcome(self) -> AuthOutcome {
+        match self {
+            Rejection::Missing => AuthOutcome::Missing,
+            Rejection::Invalid => AuthOutcome::Invalid,
+            Rejection::Denied => AuthOutcome::Denied,
+        }
+    }
+
+    // For reads of private buckets: missing and unknown credentials get a
+    // 401 offering Basic, so browsers prompt (again) for them.
+    pub fn challenge(self, realm: &str) -> Response {
+        if self == Rejection::Denied {
+            return self.into_response();
+        }
+        let realm: String = realm
+            .chars()
+            .map(|c| if c == '"' || c == '\\' { '_' } else { c })
+            .collect();
+        let mut response = StatusCode::UNAUTHORIZED.into_response();
+        let headers = response.headers_mut();
+        for challenge in [
+            format!("Basic realm=\"{}\", charset=\"UTF-8\"", realm),
+            format!("Bearer realm=\"{}\"", realm),
+        ] {
+            if let Ok(value) = HeaderValue::from_str(&challenge) {
+                headers.append(header::WWW_AUTHENTICATE, value);
+            }
+        }
+        response.extensions_mut().insert(self.outcome());
+        response
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let mut response = match self {
+            Rejection::Missing => (
+                StatusCode::UNAUTHORIZED,
+                [(header::WWW_AUTHENTICATE, "Bearer")],
+            )
+                .into_response(),
+            Rejection::Invalid | Rejection::Denied => StatusCode::FORBIDDEN.into_response(),
+        };
+        response.extensions_mut().insert(self.outcome());
+        response
+    }
+}
+
+pub fn granted(mut response: Response) -> Response {
+    response.extensions_mut().insert(AuthOutcome::Granted);
+    response
+}
+
+// Compares secrets without an early exit on the first differing byte.
+pub fn secrets_equal(a: &str, b: &str) -> bool {
+    a.as_bytes().ct_eq(b.as_bytes()).into()
+}
+
+#[derive(Clone)]
+enum Secret {
+    Plain(String),
+    Sha256([u8; 32]),
+    // PHC string, verified through `VerifiedTokens`
+    Argon2(String),
+    // Built for a single request from a verified JWT; never matches a
+    // presented token
+    Jwt,
+}
+
+#[derive(Clone)]
+pub struct Token {
+    pub name: String,
+    secret: Secret,
+    pub scopes: Vec<Scope>,
+    pub paths: Vec<String>,
+}
+
+pub fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn hex_decode(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+fn sha256(value: &str) -> [u8; 32] {
+    Sha256::digest(value.as_bytes()).into()
+}
+
+fn parse_hash(hash: &str) -> Result<Secret, String> {
+    if let Some(hex) = hash.strip_prefix("sha256:") {
+        return hex_decode(hex)
+            .and_then(|digest| digest.try_into().ok())
+            .map(Secret::Sha256)
+            .ok_or_else(|| "sha256 hash must be 64 hex digits".to_string());
+    }
+    if hash.starts_with("$argon2") {
+        PasswordHash::new(hash).map_err(|e| e.to_string())?;
+        return Ok(Secret::Argon2(hash.to_string()));
+    }
+    Err("expected an `$argon2id$...` or `sha256:...` hash".to_string())
+}
+
+fn secret(
+    name: &str,
+    token: Option<&String>,
+    token_hash: Option<&String>,
+) -> Result<Secret, Box<dyn std::error::Error>> {
+    match (token, token_hash) {
+        (Some(token), None) if !token.is_empty() => Ok(Secret::Plain(token.clone())),
+        (Some(_), None) => Err(format!("token {:?} has an empty token", name).into()),
+        (None, Some(hash)) => parse_hash(hash)
+            .map_err(|e| format!("token {:?}: invalid token_hash: {}", name, e).into()),
+        (Some(_), Some(_)) => {
+            Err(format!("token {:?} sets both token and token_hash", name).into())
+        }
+        (None, None) => Err(format!("token {:?} needs a token or token_hash", name).into()),
+    }
+}
+
+// Turns `[auth]` into the bucket's token list, with the legacy `token` as
+// an unrestricted admin token named `default`.
+pub fn compile(auth: &BucketAuth) -> Result<Vec<Token>, Box<dyn std::error::Error>> {
+    let mut tokens = Vec::new();
+    if auth.token.is_some() || auth.token_hash.is_some() {
+        tokens.push(Token {
+            name: "default".to_string(),
+            secret: secret("default", auth.token.as_ref(), auth.token_hash.as_ref())?,
+            scopes: vec![Scope::Admin],
+            paths: Vec::new(),
+        });
+    }
+
+    let mut names = HashSet::new();
+    if !tokens.is_empty() {
+        names.insert("default");
+    }
+    for token in &auth.tokens {
+        if token.scopes.is_empty() {
+            return Err(format!("token {:?} has no scopes", token.name).into());
+        }
+        if !names.insert(token.name.as_str()) {
+            return Err(format!("duplicate token name {:?}", token.name).into());
+        }
+        let paths: Vec<String> = token
+            .paths
+            .iter()
+            .map(|path| path.trim_matches('/').to_string())
+            .collect();
+        if paths.iter().any(|path| path.is_empty()) {
+            return Err(format!("token {:?} has an empty path prefix", token.name).into());
+        }
+        tokens.push(Token {
+            name: token.name.clone(),
+            secret: secret(&token.name, token.token.as_ref(), token.token_hash.as_ref())?,
+            scopes: token.scopes.clone(),
+            paths,
+        });
+    }
+    Ok(tokens)
+}
+
+// Buckets are loaded per request, so argon2 results are remembered here to
+// keep its deliberately slow verification off every later request with the
+// same token. Keyed by the stored hash and the SHA-256 of the presented
+// token; the token itself is never kept.
+#[derive(Default)]
+pub struct VerifiedTokens {
+    results: Mutex<HashMap<(String, [u8; 32]), bool>>,
+}
+
+const VERIFIED_CAPACITY: usize = 1024;
+
+impl VerifiedTokens {
+    // Argon2 takes tens of milliseconds of CPU, so it runs on the blocking
+    // pool rather than holding up a runtime worker.
+    async fn verify(&self, hash: &str, presented: &str) -> bool {
+        let key = (hash.to_string(), sha256(presented));
+        if let Ok(results) = self.results.lock()
+            && let Some(&matches) = results.get(&key)
+        {
+            return matches;
+        }
+
+        let (stored, presented) = (hash.to_string(), presented.to_string());
+        let matches = tokio::task::spawn_blocking(move || {
+            PasswordHash::new(&stored).is_ok_and(|parsed| {
+                Argon2::default()
+                    .verify_password(presented.as_bytes(), &parsed)
+                    .is_ok()
+            })
+        })
+        .await
+        .unwrap_or(false);
+        if let Ok(mut results) = self.results.lock() {
+            if results.len() >= VERIFIED_CAPACITY {
+                results.clear();
+            }
+            results.insert(key, matches);
+        }
+        matches
+    }
+}
+
+impl Token {
+    async fn matches(&self, presented: &str, verified: &VerifiedTokens) -> bool {
+        match &self.secret {
+            Secret::Plain(token) => secrets_equal(token, presented),
+            Secret::Sha256(digest) => digest.ct_eq(&sha256(presented)).into(),
+            Secret::Argon2(hash) => verified.verify(hash, presented).await,
+            Secret::Jwt => false,
+        }
+    }
+}
+
+// Authentication state shared by every bucket of the server
+#[derive(Default)]
+pub struct Credentials {
+    verified: VerifiedTokens,
+    jwt: Option<JwtVerifier>,
+}
+
+impl Credentials {
+    pub fn with_jwt(jwt: JwtVerifier) -> Self {
+        Self {
+            verified: VerifiedTokens::default(),
+            jwt: Some(jwt),
+        }
+    }
+
+    // Values shaped like a JWT are tried as one first, so each freshly
+    // minted JWT does not also cost an argon2 run per hashed bucket token.
+    // A valid JWT only grants the scopes its claims list for `bucket`, so it
+    // may still grant nothing.
+    pub async fn find<'a>(
+        &self,
+        tokens: &'a [Token],
+        bucket: &str,
+        presented: &str,
+    ) -> Option<Cow<'a, Token>> {
+        if let Some(jwt) = &self.jwt
+            && looks_like_jwt(presented)
+            && let Some((subject, scopes)) = jwt.verify(presented, bucket)
+        {
+            return Some(Cow::Owned(Token {
+                name: subject,
+                secret: Secret::Jwt,
+                scopes,
+                paths: Vec::new(),
+            }));
+        }
+        for token in tokens {
+            if token.matches(presented, &self.verified).await {
+                return Some(Cow::Borrowed(token));
+            }
+        }
+        None
+    }
+}
+
+// Three dot-separated parts, the first a base64url JSON object
+fn looks_like_jwt(value: &str) -> bool {
+    value.starts_with("eyJ") && value.split('.').count() == 3
+}
+
+// `stathost hash-token`: argon2id by default, or SHA-256 for deployments
+// that cannot afford argon2 on cold requests.
+pub fn hash_token(token: &str, use_sha256: bool) -> Result<String, String> {
+    if use_sha256 {
+        return Ok(format!("sha256:{}", hex_encode(&sha256(token))));
+    }
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(token.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| e.to_string())
+}
+
+fn has_scope(token: &Token, scope: Scope) -> bool {
+    token
+        .scopes
+        .iter()
+        .any(|&granted| granted == scope || granted == Scope::Admin)
+}
+
+// Whether `path` (relative to the bucket) lies within the token's path
+// prefixes. Prefixes match whole segments, so `releases` covers
+// `releases/v1.zip` but not `releases-old/`.
+pub fn covers(token: &Token, path: &str) -> bool {
+    if token.paths.is_empty() {
+        return true;
+    }
+    let path = path.trim_start_matches('/');
+    // `..` could step out of the prefix before the path is resolved
+    if path.split('/').any(|segment| segment == "..") {
+        return false;
+    }
+    token.paths.iter().any(|prefix| {
+        path.strip_prefix(prefix.as_str())
+            .is_some_and(|rest| rest.is_empty() || rest.starts_with('/'))
+    })
+}
+
+// Finds the request's token and checks it for `scope`, and for `path` when
+// the operation is on a single file.
+pub async fn authorize<'a>(
+    bucket: &'a Bucket,
+    headers: &HeaderMap,
+    scope: Scope,
+    path: Option<&str>,
+) -> Result<Cow<'a, Token>, Rejection> {
+    let token = extract_token(headers).ok_or(Rejection::Missing)?;
+    let token = bucket.find_token(&token).await.ok_or(Rejection::Invalid)?;
+    if !has_scope(&token, scope) || !path.is_none_or(|path| covers(&token, path)) {
+        return Err(Rejection::Denied);
+    }
+    Ok(token)
 }
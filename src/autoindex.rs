@@ -0,0 +1,187 @@
+use axum::{
+    Json,
+    http::{HeaderMap, StatusCode, header},
+    response::{Html, IntoResponse, Response},
+};
+use std::time::{Duration, UNIX_EPOCH};
+
+use crate::bucket::{Bucket, FileEntry};
+
+#[derive(Clone, Copy, PartialEq)]
+enum SortKey {
+    Name,
+    Size,
+    Mtime,
+}
+
+impl SortKey {
+    fn as_str(self) -> &'static str {
+        match self {
+            SortKey::Name => "name",
+            SortKey::Size => "size",
+            SortKey::Mtime => "mtime",
+        }
+    }
+}
+
+fn parse_sort(query: Option<&str>) -> (SortKey, bool) {
+    let mut key = SortKey::Name;
+    let mut descending = false;
+    for pair in query.unwrap_or_default().split('&') {
+        match pair.split_once('=') {
+            Some(("sort", "name")) => key = SortKey::Name,
+            Some(("sort", "size")) => key = SortKey::Size,
+            Some(("sort", "mtime")) => key = SortKey::Mtime,
+            Some(("order", "desc")) => descending = true,
+            Some(("order", "asc")) => descending = false,
+            _ => {}
+        }
+    }
+    (key, descending)
+}
+
+fn is_dir(entry: &FileEntry) -> bool {
+    entry.path.ends_with('/')
+}
+
+fn display_name(entry: &FileEntry) -> &str {
+    let path = entry.path.trim_end_matches('/');
+    path.rsplit('/').next().unwrap_or(path)
+}
+
+// Directories are always listed before files, whatever the sort key.
+fn sort_entries(entries: &mut [FileEntry], key: SortKey, descending: bool) {
+    entries.sort_by(|a, b| {
+        let order = match key {
+            SortKey::Name => display_name(a).cmp(display_name(b)),
+            SortKey::Size => a.size.cmp(&b.size),
+            SortKey::Mtime => a.mtime.cmp(&b.mtime),
+        }
+        .then_with(|| display_name(a).cmp(display_name(b)));
+        let order = if descending { order.reverse() } else { order };
+        is_dir(b).cmp(&is_dir(a)).then(order)
+    });
+}
+
+fn escape_html(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+pub fn encode_segment(value: &str) -> String {
+    let mut encoded = String::with_capacity(value.len());
+    for byte in value.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    encoded
+}
+
+fn header_link(label: &str, key: SortKey, current: SortKey, descending: bool) -> String {
+    let order = if key == current && !descending {
+        "desc"
+    } else {
+        "asc"
+    };
+    format!(
+        "<th><a href=\"?sort={}&amp;order={}\">{}</a></th>",
+        key.as_str(),
+        order,
+        label
+    )
+}
+
+fn render_html(dir: &str, entries: &[FileEntry], key: SortKey, descending: bool) -> String {
+    let title = escape_html(&format!("Index of /{}", dir));
+    let mut html = format!(
+        "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{title}</title>\n</head>\n\
+         <body>\n<h1>{title}</h1>\n<table>\n<thead><tr>{}{}{}</tr></thead>\n<tbody>\n",
+        header_link("Name", SortKey::Name, key, descending),
+        header_link("Size", SortKey::Size, key, descending),
+        header_link("Modified", SortKey::Mtime, key, descending),
+    );
+
+    if !dir.is_empty() {
+        html.push_str("<tr><td><a href=\"../\">../</a></td><td></td><td></td></tr>\n");
+    }
+
+    for entry in entries {
+        let name = display_name(entry);
+        let (href, label, size) = if is_dir(entry) {
+            (
+                format!("{}/", encode_segment(name)),
+                format!("{}/", name),
+                "-".to_string(),
+            )
+        } else {
+            (
+                encode_segment(name),
+                name.to_string(),
+                entry.size.to_string(),
+            )
+        };
+        let modified = httpdate::fmt_http_date(UNIX_EPOCH + Duration::from_secs(entry.mtime));
+        html.push_str(&format!(
+            "<tr><td><a href=\"{}\">{}</a></td><td>{}</td><td>{}</td></tr>\n",
+            href,
+            escape_html(&label),
+            size,
+            modified
+        ));
+    }
+
+    html.push_str("</tbody>\n</table>\n</body>\n</html>\n");
+    html
+}
+
+fn wants_json(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::ACCEPT)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.contains("application/json"))
+}
+
+pub async fn render(
+    bucket: &Bucket,
+    dir: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+) -> Response {
+    let mut entries = match bucket.list_directory(dir).await {
+        Ok(entries) => entries,
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::NotFound | std::io::ErrorKind::NotADirectory
+            ) =>
+        {
+            return StatusCode::NOT_FOUND.into_response();
+        }
+        Err(_) => return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    };
+
+    let (key, descending) = parse_sort(query);
+    sort_entries(&mut entries, key, descending);
+
+    // The format follows Accept, so caches must keep both apart
+    let vary = [(header::VARY, "Accept")];
+    if wants_json(headers) {
+        (vary, Json(entries)).into_response()
+    } else {
+        (vary, Html(render_html(dir, &entries, key, descending))).into_response()
+    }
+}
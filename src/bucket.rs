@@ -1,5 +1,22 @@
-use crate::config::BucketConfig;
+use crate::auth::{self, Credentials, Token};
+use crate::client_cert::{self, ClientCertPolicy};
+use crate::config::{AccessLogFormat, BucketConfig, RedirectRule};
+use crate::cors;
+use crate::drain::Drain;
+use crate::encoding::PRECOMPRESSED;
+use crate::ip_filter::{IpAccess, TrustedProxies};
+use crate::jwt::JwtVerifier;
+use crate::metrics::Metrics;
+use crate::presign;
+use crate::rate_limit::RateLimiter;
+use crate::redirect;
+use crate::response_headers::{self, HeaderSet};
+use crate::vhost;
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
 use tokio::fs;
 
 pub const TMP_SUFFIX: &str = ".stathost-tmp";
@@ -14,26 +31,119 @@ pub struct FileEntry {
 pub struct Bucket {
     path: PathBuf,
     config: BucketConfig,
+    redirects: Vec<RedirectRule>,
+    headers: Vec<HeaderSet>,
+    tokens: Vec<Token>,
+    client_certs: Option<ClientCertPolicy>,
+    ip_access: IpAccess,
+    credentials: Arc<Credentials>,
 }
 
 impl Bucket {
-    pub fn load(path: PathBuf) -> Result<Self, Box<dyn std::error::Error>> {
+    pub fn load(
+        path: PathBuf,
+        credentials: Arc<Credentials>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
         let config = BucketConfig::load(&path)?;
-        Ok(Self { path, config })
+
+        let mut redirects = config.redirects.clone();
+        for rule in &redirects {
+            redirect::validate(rule)?;
+        }
+        if let Some(name) = &config.serve.redirects_file {
+            match std::fs::read_to_string(path.join(name)) {
+                Ok(content) => redirects.extend(redirect::parse_redirects_file(&content)?),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+
+        let headers = response_headers::compile(&config.headers)?;
+        let tokens = auth::compile(&config.auth)?;
+        let client_certs = config
+            .auth
+            .client_certs
+            .as_ref()
+            .map(|client_certs| client_cert::compile(client_certs, &path))
+            .transpose()?;
+        let ip_access = IpAccess::compile(&config.ip)?;
+        if let Some(cors) = &config.cors {
+            cors::validate(cors)?;
+        }
+        if let Some(presign) = &config.presign {
+            presign::validate(presign)?;
+        }
+
+        Ok(Self {
+            path,
+            config,
+            redirects,
+            headers,
+            tokens,
+            client_certs,
+            ip_access,
+            credentials,
+        })
+    }
+
+    pub fn redirects(&self) -> &[RedirectRule] {
+        &self.redirects
+    }
+
+    pub fn header_sets(&self) -> &[HeaderSet] {
+        &self.headers
+    }
+
+    pub fn relative_path(&self, path: &Path) -> String {
+        path.strip_prefix(&self.path)
+            .unwrap_or(path)
+            .to_string_lossy()
+            .to_string()
+    }
+
+    pub fn config(&self) -> &BucketConfig {
+        &self.config
+    }
+
+    pub fn name(&self) -> String {
+        self.path
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    }
+
+    pub fn ip_access(&self) -> &IpAccess {
+        &self.ip_access
+    }
+
+    pub fn client_certs(&self) -> Option<&ClientCertPolicy> {
+        self.client_certs.as_ref()
     }
 
-    pub fn validate_token(&self, token: &str) -> bool {
-        self.config.auth.token == token
+    pub async fn find_token(&self, token: &str) -> Option<Cow<'_, Token>> {
+        self.credentials
+            .find(&self.tokens, &self.name(), token)
+            .await
     }
 
     pub fn resolve_path(&self, file_path: &str) -> Option<PathBuf> {
-        let file_path = file_path.trim_start_matches('/');
+        // Checked on the normalized path, so `a/../config.toml` or
+        // `./config.toml` cannot reach protected files
+        let mut segments = Vec::new();
+        for segment in file_path.split('/') {
+            match segment {
+                "" | "." => {}
+                ".." => return None,
+                segment => segments.push(segment),
+            }
+        }
+        let file_path = segments.join("/");
 
-        if is_protected_path(file_path) {
+        if is_protected_path(&file_path) {
             return None;
         }
 
-        let full_path = self.path.join(file_path);
+        let full_path = self.path.join(&file_path);
 
         if !is_safe_path(&self.path, &full_path) {
             return None;
@@ -42,35 +152,107 @@ impl Bucket {
         Some(full_path)
     }
 
-    pub async fn list_files(&self) -> Result<Vec<String>, std::io::Error> {
-        let mut files = Vec::new();
-        collect_files(&self.path, &self.path, &mut files).await?;
+    pub async fn list_files(&self, precompressed: bool) -> Result<Vec<String>, std::io::Error> {
+        let files = self.collect(precompressed).await?;
         Ok(files.into_iter().map(|(relative, _)| relative).collect())
     }
 
-    pub async fn list_files_detailed(&self) -> Result<Vec<FileEntry>, std::io::Error> {
-        let mut files = Vec::new();
-        collect_files(&self.path, &self.path, &mut files).await?;
+    pub async fn list_files_detailed(
+        &self,
+        precompressed: bool,
+    ) -> Result<Vec<FileEntry>, std::io::Error> {
+        let files = self.collect(precompressed).await?;
 
         let mut entries = Vec::with_capacity(files.len());
         for (relative, path) in files {
             let Ok(metadata) = fs::metadata(&path).await else {
                 continue;
             };
-            let mtime = metadata
-                .modified()
-                .ok()
-                .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
-                .map(|d| d.as_secs())
-                .unwrap_or(0);
-            entries.push(FileEntry {
-                path: relative,
-                size: metadata.len(),
-                mtime,
-            });
+            entries.push(file_entry(relative, &metadata));
         }
         Ok(entries)
     }
+
+    // Lists the immediate children of `dir` for autoindex pages.
+    // Subdirectories are returned with a trailing `/` and size 0.
+    pub async fn list_directory(&self, dir: &str) -> Result<Vec<FileEntry>, std::io::Error> {
+        let dir = self.path.join(dir.trim_start_matches('/'));
+        let mut entries = fs::read_dir(&dir).await?;
+
+        let mut files = Vec::new();
+        let mut listing = Vec::new();
+        while let Some(entry) = entries.next_entry().await? {
+            let path = entry.path();
+            let relative = path
+                .strip_prefix(&self.path)
+                .unwrap_or(&path)
+                .to_string_lossy()
+                .to_string();
+
+            if is_protected_path(&relative) {
+                continue;
+            }
+
+            let Ok(metadata) = fs::metadata(&path).await else {
+                continue;
+            };
+            if metadata.is_dir() {
+                let mut entry = file_entry(format!("{}/", relative), &metadata);
+                entry.size = 0;
+                listing.push(entry);
+            } else {
+                files.push((relative, path));
+            }
+        }
+
+        for (relative, path) in without_sidecars(files) {
+            let Ok(metadata) = fs::metadata(&path).await else {
+                continue;
+            };
+            listing.push(file_entry(relative, &metadata));
+        }
+        Ok(listing)
+    }
+
+    async fn collect(&self, precompressed: bool) -> Result<Vec<(String, PathBuf)>, std::io::Error> {
+        let mut files = Vec::new();
+        collect_files(&self.path, &self.path, &mut files).await?;
+        if precompressed {
+            Ok(files)
+        } else {
+            Ok(without_sidecars(files))
+        }
+    }
+}
+
+fn file_entry(path: String, metadata: &std::fs::Metadata) -> FileEntry {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    FileEntry {
+        path,
+        size: metadata.len(),
+        mtime,
+    }
+}
+
+// `.br`/`.gz` sidecars of another file in the bucket are an implementation
+// detail of serving that file and are left out unless asked for.
+fn without_sidecars(files: Vec<(String, PathBuf)>) -> Vec<(String, PathBuf)> {
+    let names: HashSet<String> = files.iter().map(|(relative, _)| relative.clone()).collect();
+    files
+        .into_iter()
+        .filter(|(relative, _)| {
+            !PRECOMPRESSED.iter().any(|(_, ext)| {
+                relative
+                    .strip_suffix(ext)
+                    .is_some_and(|original| names.contains(original))
+            })
+        })
+        .collect()
 }
 
 fn is_protected_path(path: &str) -> bool {
@@ -136,44 +318,238 @@ async fn collect_files(
     Ok(())
 }
 
-pub async fn cleanup_temp_files(dir: &Path) -> Result<(), std::io::Error> {
+// Returns the number of temp files removed.
+pub async fn cleanup_temp_files(dir: &Path) -> Result<usize, std::io::Error> {
     let mut entries = fs::read_dir(dir).await?;
+    let mut removed = 0;
 
     while let Some(entry) = entries.next_entry().await? {
         let path = entry.path();
         if path.is_dir() {
-            Box::pin(cleanup_temp_files(&path)).await?;
+            removed += Box::pin(cleanup_temp_files(&path)).await?;
         } else if path
             .file_name()
             .and_then(|n| n.to_str())
             .is_some_and(|n| n.ends_with(TMP_SUFFIX))
+            && fs::remove_file(&path).await.is_ok()
         {
-            let _ = fs::remove_file(&path).await;
+            removed += 1;
         }
     }
 
-    Ok(())
+    Ok(removed)
+}
+
+// How long the `domains` collected from bucket configs are reused before
+// the buckets directory is scanned again.
+const DOMAIN_REFRESH: Duration = Duration::from_secs(5);
+
+#[derive(Default)]
+struct DomainIndex {
+    loaded_at: Option<Instant>,
+    rules: Arc<Vec<(String, String)>>,
+}
+
+// The bucket a request addresses, loaded once by the outermost middleware
+// that needs it and handed on in the request's extensions, so the whole
+// request sees one config. `bucket` is `None` when the bucket exists but
+// its config fails to load.
+#[derive(Clone)]
+pub struct LoadedBucket {
+    pub name: String,
+    bucket: Option<Arc<Bucket>>,
+}
+
+impl LoadedBucket {
+    pub fn bucket(&self) -> Option<&Bucket> {
+        self.bucket.as_deref()
+    }
 }
 
 pub struct BucketManager {
     buckets_dir: PathBuf,
+    error_template: Option<String>,
+    hosts: Vec<(String, String)>,
+    domains: RwLock<DomainIndex>,
+    drain: Drain,
+    metrics: Metrics,
+    access_log_format: AccessLogFormat,
+    credentials: Arc<Credentials>,
+    trusted_proxies: TrustedProxies,
+    ip_access: IpAccess,
+    rate_limiter: Option<RateLimiter>,
 }
 
 impl BucketManager {
     pub fn new(buckets_dir: PathBuf) -> Self {
-        Self { buckets_dir }
+        Self {
+            buckets_dir,
+            error_template: None,
+            hosts: Vec::new(),
+            domains: RwLock::new(DomainIndex::default()),
+            drain: Drain::default(),
+            metrics: Metrics::default(),
+            access_log_format: AccessLogFormat::default(),
+            credentials: Arc::default(),
+            trusted_proxies: TrustedProxies::default(),
+            ip_access: IpAccess::default(),
+            rate_limiter: None,
+        }
     }
 
-    pub fn get_bucket(&self, name: &str) -> Option<Bucket> {
-        if name.contains("..") || name.contains('/') || name.contains('\\') {
-            return None;
+    pub fn with_trusted_proxies(mut self, proxies: TrustedProxies) -> Self {
+        self.trusted_proxies = proxies;
+        self
+    }
+
+    pub fn trusted_proxies(&self) -> &TrustedProxies {
+        &self.trusted_proxies
+    }
+
+    pub fn with_ip_access(mut self, access: IpAccess) -> Self {
+        self.ip_access = access;
+        self
+    }
+
+    pub fn ip_access(&self) -> &IpAccess {
+        &self.ip_access
+    }
+
+    pub fn with_rate_limiter(mut self, limiter: RateLimiter) -> Self {
+        self.rate_limiter = Some(limiter);
+        self
+    }
+
+    pub fn rate_limiter(&self) -> Option<&RateLimiter> {
+        self.rate_limiter.as_ref()
+    }
+
+    pub fn with_jwt(mut self, jwt: JwtVerifier) -> Self {
+        self.credentials = Arc::new(Credentials::with_jwt(jwt));
+        self
+    }
+
+    pub fn with_access_log_format(mut self, format: AccessLogFormat) -> Self {
+        self.access_log_format = format;
+        self
+    }
+
+    pub fn access_log_format(&self) -> AccessLogFormat {
+        self.access_log_format
+    }
+
+    pub fn with_metrics_token(mut self, token: String) -> Self {
+        self.metrics = Metrics::with_token(token);
+        self
+    }
+
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn drain(&self) -> &Drain {
+        &self.drain
+    }
+
+    pub fn with_hosts(mut self, hosts: HashMap<String, String>) -> Self {
+        self.hosts = hosts
+            .into_iter()
+            .map(|(host, bucket)| (vhost::normalize_host(&host), bucket))
+            .collect();
+        self
+    }
+
+    // Server-wide `[server.hosts]` take precedence over bucket `domains`.
+    pub fn bucket_for_host(&self, host: &str) -> Option<String> {
+        vhost::resolve(&self.hosts, host).or_else(|| vhost::resolve(&self.domain_rules(), host))
+    }
+
+    fn domain_rules(&self) -> Arc<Vec<(String, String)>> {
+        if let Ok(index) = self.domains.read()
+            && index
+                .loaded_at
+                .is_some_and(|loaded_at| loaded_at.elapsed() < DOMAIN_REFRESH)
+        {
+            return index.rules.clone();
         }
 
-        let bucket_path = self.buckets_dir.join(name);
-        if !bucket_path.is_dir() {
+        let rules = Arc::new(self.scan_domains());
+        if let Ok(mut index) = self.domains.write() {
+            index.loaded_at = Some(Instant::now());
+            index.rules = rules.clone();
+        }
+        rules
+    }
+
+    fn scan_domains(&self) -> Vec<(String, String)> {
+        let mut rules = Vec::new();
+        for name in self.bucket_names() {
+            // Broken configs are reported when the bucket itself is loaded
+            let Ok(config) = BucketConfig::load(&self.buckets_dir.join(&name)) else {
+                continue;
+            };
+            for domain in config.domains {
+                rules.push((vhost::normalize_host(&domain), name.clone()));
+            }
+        }
+        rules
+    }
+
+    pub fn with_error_template(mut self, template: String) -> Self {
+        self.error_template = Some(template);
+        self
+    }
+
+    pub fn error_template(&self) -> Option<&str> {
+        self.error_template.as_deref()
+    }
+
+    pub fn has_bucket(&self, name: &str) -> bool {
+        !name.is_empty()
+            && !name.contains("..")
+            && !name.contains('/')
+            && !name.contains('\\')
+            && self.buckets_dir.join(name).is_dir()
+    }
+
+    // Names of all bucket directories, sorted
+    pub fn bucket_names(&self) -> Vec<String> {
+        let Ok(entries) = std::fs::read_dir(&self.buckets_dir) else {
+            return Vec::new();
+        };
+        let mut names: Vec<String> = entries
+            .flatten()
+            .filter(|entry| entry.path().is_dir())
+            .filter_map(|entry| entry.file_name().to_str().map(str::to_string))
+            .collect();
+        names.sort();
+        names
+    }
+
+    pub fn load_bucket(&self, name: String) -> LoadedBucket {
+        let bucket = self.get_bucket(&name).map(Arc::new);
+        LoadedBucket { name, bucket }
+    }
+
+    // The bucket `name` as already loaded for this request, or else from disk
+    pub fn loaded_bucket(&self, loaded: Option<&LoadedBucket>, name: &str) -> Option<Arc<Bucket>> {
+        match loaded {
+            Some(loaded) if loaded.name == name => loaded.bucket.clone(),
+            _ => self.get_bucket(name).map(Arc::new),
+        }
+    }
+
+    pub fn get_bucket(&self, name: &str) -> Option<Bucket> {
+        if !self.has_bucket(name) {
             return None;
         }
 
-        Bucket::load(bucket_path).ok()
+        match Bucket::load(self.buckets_dir.join(name), self.credentials.clone()) {
+            Ok(bucket) => Some(bucket),
+            Err(e) => {
+                eprintln!("Failed to load bucket {}: {}", name, e);
+                None
+            }
+        }
     }
 }
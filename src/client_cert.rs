@@ -0,0 +1,214 @@
+use axum::http::Extensions;
+use rustls::{
+    DigitallySignedStruct, DistinguishedName, RootCertStore, SignatureScheme,
+    client::danger::HandshakeSignatureValid,
+    crypto::{CryptoProvider, ring, verify_tls12_signature, verify_tls13_signature},
+    server::{
+        WebPkiClientVerifier,
+        danger::{ClientCertVerified, ClientCertVerifier},
+    },
+};
+use rustls_pki_types::{CertificateDer, UnixTime, pem::PemObject};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use std::sync::Arc;
+use x509_parser::prelude::{FromDer, X509Certificate};
+
+use crate::auth::hex_decode;
+use crate::bucket::Bucket;
+use crate::config::ClientCertAuth;
+
+// The chain a TLS client presented, leaf first; empty without one. Added to
+// every request on a TLS connection.
+#[derive(Clone)]
+pub struct ClientCert(pub Arc<Vec<CertificateDer<'static>>>);
+
+// Asks TLS clients for a certificate but lets any chain through the
+// handshake, which does prove the client holds the certificate's key. Which
+// chains are trusted is up to each bucket, checked per request.
+#[derive(Debug)]
+pub struct AnyClientCert {
+    provider: Arc<CryptoProvider>,
+}
+
+impl AnyClientCert {
+    pub fn new(provider: Arc<CryptoProvider>) -> Self {
+        Self { provider }
+    }
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+// A bucket's `[auth.client_certs]`, ready to check chains against
+pub struct ClientCertPolicy {
+    verifier: Option<Arc<dyn ClientCertVerifier>>,
+    subjects: Vec<String>,
+    fingerprints: Vec<[u8; 32]>,
+}
+
+fn parse_fingerprint(fingerprint: &str) -> Option<[u8; 32]> {
+    // `openssl x509 -fingerprint -sha256` prints colon separated hex
+    let hex: String = fingerprint
+        .strip_prefix("sha256:")?
+        .chars()
+        .filter(|&c| c != ':')
+        .collect();
+    hex_decode(&hex)?.try_into().ok()
+}
+
+pub fn compile(
+    config: &ClientCertAuth,
+    bucket_path: &Path,
+) -> Result<ClientCertPolicy, Box<dyn std::error::Error>> {
+    if config.ca.is_none() && config.fingerprints.is_empty() {
+        return Err("client_certs needs a ca or fingerprints".into());
+    }
+    // Anyone can issue themselves a certificate with any subject
+    if config.ca.is_none() && !config.subjects.is_empty() {
+        return Err("client_certs subjects need a ca".into());
+    }
+
+    let verifier = match &config.ca {
+        Some(ca) => {
+            let path = bucket_path.join(ca);
+            let mut roots = RootCertStore::empty();
+            for cert in CertificateDer::pem_file_iter(&path)
+                .map_err(|e| format!("{}: {}", path.display(), e))?
+            {
+                roots.add(cert.map_err(|e| format!("{}: {}", path.display(), e))?)?;
+            }
+            if roots.is_empty() {
+                return Err(format!("{}: no certificates found", path.display()).into());
+            }
+            let verifier = WebPkiClientVerifier::builder_with_provider(
+                Arc::new(roots),
+                Arc::new(ring::default_provider()),
+            )
+            .build()?;
+            Some(verifier)
+        }
+        None => None,
+    };
+
+    let fingerprints = config
+        .fingerprints
+        .iter()
+        .map(|fingerprint| {
+            parse_fingerprint(fingerprint).ok_or_else(|| {
+                format!(
+                    "invalid client certificate fingerprint {:?}: expected sha256:<64 hex digits>",
+                    fingerprint
+                )
+            })
+        })
+        .collect::<Result<_, _>>()?;
+
+    Ok(ClientCertPolicy {
+        verifier,
+        subjects: config.subjects.clone(),
+        fingerprints,
+    })
+}
+
+impl ClientCertPolicy {
+    // A pinned fingerprint accepts the certificate on its own, as long as it
+    // has not expired. Otherwise the chain has to lead to the bucket's CA,
+    // and its leaf match one of `subjects` (full DN or CN) if any are set.
+    fn accepts(&self, chain: &[CertificateDer<'static>]) -> bool {
+        let Some((leaf, intermediates)) = chain.split_first() else {
+            return false;
+        };
+        let Ok((_, parsed)) = X509Certificate::from_der(leaf) else {
+            return false;
+        };
+
+        let fingerprint: [u8; 32] = Sha256::digest(leaf).into();
+        if self.fingerprints.contains(&fingerprint) {
+            return parsed.validity().is_valid();
+        }
+
+        let Some(verifier) = &self.verifier else {
+            return false;
+        };
+        if verifier
+            .verify_client_cert(leaf, intermediates, UnixTime::now())
+            .is_err()
+        {
+            return false;
+        }
+        if self.subjects.is_empty() {
+            return true;
+        }
+        let subject = parsed.subject();
+        let dn = subject.to_string();
+        let cn = subject
+            .iter_common_name()
+            .next()
+            .and_then(|cn| cn.as_str().ok());
+        self.subjects
+            .iter()
+            .any(|accepted| *accepted == dn || Some(accepted.as_str()) == cn)
+    }
+}
+
+// Whether the request came over TLS with a client certificate the bucket
+// accepts in place of a token.
+pub fn accepted(bucket: &Bucket, extensions: &Extensions) -> bool {
+    let (Some(policy), Some(ClientCert(chain))) =
+        (bucket.client_certs(), extensions.get::<ClientCert>())
+    else {
+        return false;
+    };
+    policy.accepts(chain)
+}
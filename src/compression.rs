@@ -0,0 +1,137 @@
+use axum::{
+    body::Body,
+    http::{HeaderValue, Response, StatusCode, header},
+};
+use std::sync::Arc;
+use tower::{
+    ServiceBuilder,
+    layer::util::{Identity, Stack},
+    util::MapResponseLayer,
+};
+use tower_http::compression::{CompressionBody, CompressionLayer, predicate::Predicate};
+
+use crate::config::{BucketCompression, CompressionAlgorithm, CompressionConfig};
+
+// Only responses carrying a `BucketCompression` extension are candidates;
+// `serve_file` attaches one to file bodies, so metadata-only responses such
+// as HEAD keep their Content-Length.
+#[derive(Clone)]
+pub struct CompressionPredicate {
+    enabled: bool,
+    mime_types: Arc<Vec<String>>,
+    min_size: u64,
+}
+
+impl CompressionPredicate {
+    fn new(config: &CompressionConfig) -> Self {
+        Self {
+            enabled: config.enabled,
+            mime_types: Arc::new(config.mime_types.clone()),
+            min_size: config.min_size,
+        }
+    }
+}
+
+fn mime_matches(allowed: &[String], content_type: &str) -> bool {
+    let essence = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    allowed
+        .iter()
+        .any(|pattern| match pattern.strip_suffix("/*") {
+            Some(prefix) => essence
+                .split_once('/')
+                .is_some_and(|(kind, _)| kind == prefix),
+            None => pattern.eq_ignore_ascii_case(&essence),
+        })
+}
+
+impl Predicate for CompressionPredicate {
+    fn should_compress<B>(&self, response: &Response<B>) -> bool
+    where
+        B: axum::body::HttpBody,
+    {
+        let Some(bucket) = response.extensions().get::<BucketCompression>() else {
+            return false;
+        };
+        if !bucket.enabled.unwrap_or(self.enabled) || response.status() != StatusCode::OK {
+            return false;
+        }
+
+        let too_small = response
+            .headers()
+            .get(header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<u64>().ok())
+            .is_some_and(|len| len < self.min_size);
+        if too_small {
+            return false;
+        }
+
+        let allowed = bucket.mime_types.as_deref().unwrap_or(&self.mime_types);
+        response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .is_some_and(|content_type| mime_matches(allowed, content_type))
+    }
+}
+
+// Marks responses that reach the compression layer without an encoding, so
+// those it encodes can be told from precompressed siblings afterwards
+#[derive(Clone, Copy)]
+struct Unencoded;
+
+fn mark_unencoded(mut response: Response<Body>) -> Response<Body> {
+    if !response.headers().contains_key(header::CONTENT_ENCODING) {
+        response.extensions_mut().insert(Unencoded);
+    }
+    response
+}
+
+// The encoded bytes differ from the file the strong ETag names, so like
+// nginx the ETag is weakened: If-None-Match still matches, If-Range no
+// longer does.
+fn weaken_etag(mut response: Response<CompressionBody<Body>>) -> Response<CompressionBody<Body>> {
+    let encoded = response.extensions_mut().remove::<Unencoded>().is_some()
+        && response.headers().contains_key(header::CONTENT_ENCODING);
+    let weak = response
+        .headers()
+        .get(header::ETAG)
+        .and_then(|etag| etag.to_str().ok())
+        .filter(|etag| encoded && !etag.starts_with("W/"))
+        .and_then(|etag| HeaderValue::from_str(&format!("W/{}", etag)).ok());
+    if let Some(weak) = weak {
+        response.headers_mut().insert(header::ETAG, weak);
+    }
+    response
+}
+
+type MapResponse<B> = MapResponseLayer<fn(Response<B>) -> Response<B>>;
+
+pub type Compression = ServiceBuilder<
+    Stack<
+        MapResponse<Body>,
+        Stack<
+            CompressionLayer<CompressionPredicate>,
+            Stack<MapResponse<CompressionBody<Body>>, Identity>,
+        >,
+    >,
+>;
+
+pub fn compression_layer(config: &CompressionConfig) -> Compression {
+    let enabled = |algorithm| config.algorithms.contains(&algorithm);
+    let compression = CompressionLayer::new()
+        .br(enabled(CompressionAlgorithm::Br))
+        .zstd(enabled(CompressionAlgorithm::Zstd))
+        .gzip(enabled(CompressionAlgorithm::Gzip))
+        .no_deflate()
+        .compress_when(CompressionPredicate::new(config));
+    ServiceBuilder::new()
+        .layer(MapResponseLayer::new(weaken_etag as fn(_) -> _))
+        .layer(compression)
+        .layer(MapResponseLayer::new(mark_unencoded as fn(_) -> _))
+}
@@ -1,4 +1,5 @@
 use serde::Deserialize;
+use std::collections::HashMap;
 use std::path::Path;
 
 #[derive(Debug, Deserialize)]
@@ -9,6 +10,205 @@ pub struct ServerConfig {
     pub port: u16,
     #[serde(default = "default_buckets_dir")]
     pub buckets_dir: String,
+    #[serde(default)]
+    pub compression: CompressionConfig,
+    // Host name -> bucket served at `/` for that host. `*.example.com`
+    // matches any subdomain; a bucket of `*` means "the subdomain's name".
+    #[serde(default)]
+    pub hosts: HashMap<String, String>,
+    pub tls: Option<TlsConfig>,
+    // When empty, a single TCP listener on `host:port` is used, or the
+    // sockets passed by systemd socket activation if there are any.
+    #[serde(default)]
+    pub listeners: Vec<ListenerConfig>,
+    // Seconds to wait for in-flight requests on shutdown before cutting
+    // them off
+    #[serde(default = "default_drain_timeout")]
+    pub drain_timeout: u64,
+    #[serde(default)]
+    pub metrics: MetricsConfig,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    pub jwt: Option<JwtConfig>,
+    // Address ranges of load balancers whose `Forwarded`/`X-Forwarded-For`
+    // headers name the client; `unix` trusts Unix socket clients
+    #[serde(default)]
+    pub trusted_proxies: Vec<String>,
+    // Server-wide client address lists, checked before the bucket's own
+    #[serde(default)]
+    pub ip: IpAccessConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct RateLimitConfig {
+    // Requests per client address and per bucket
+    pub ip: Option<RateConfig>,
+    pub bucket: Option<RateConfig>,
+    pub auth_failures: Option<AuthFailureConfig>,
+}
+
+// A token bucket: `burst` requests at once, refilled at `rate` per second
+#[derive(Debug, Clone, Copy, Deserialize)]
+pub struct RateConfig {
+    pub rate: f64,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct AuthFailureConfig {
+    // Failed token checks per client address and per bucket
+    pub ip: Option<RateConfig>,
+    pub bucket: Option<RateConfig>,
+    // Seconds credentials are refused for once either runs out
+    #[serde(default = "default_lockout")]
+    pub lockout: u64,
+}
+
+fn default_lockout() -> u64 {
+    300
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IpAccessConfig {
+    // GET, HEAD and OPTIONS
+    #[serde(default)]
+    pub read: IpRulesConfig,
+    // Uploads, deletes and presigning
+    #[serde(default)]
+    pub write: IpRulesConfig,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct IpRulesConfig {
+    // CIDR ranges or single addresses. When `allow` is set only those are
+    // let in; `deny` takes precedence over it.
+    #[serde(default)]
+    pub allow: Vec<String>,
+    #[serde(default)]
+    pub deny: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AccessLogFormat {
+    // `METHOD URI STATUS DURATIONms`
+    #[default]
+    Plain,
+    // Apache/NCSA Common and Combined Log Format
+    Common,
+    Combined,
+    // One JSON object per line
+    Json,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct AccessLogConfig {
+    #[serde(default)]
+    pub format: AccessLogFormat,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct JwtConfig {
+    // Public keys: a JWKS file (`{"keys": [...]}`) or a single PEM key
+    pub jwks: Option<String>,
+    pub pem: Option<String>,
+    // Signing algorithm of the PEM key; JWKS keys carry their own
+    #[serde(default = "default_jwt_algorithm")]
+    pub algorithm: jsonwebtoken::Algorithm,
+    // Required `iss` and `aud` values, when set
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    // Claim holding `stathost:<bucket>:<scope>` grants, either a space
+    // separated string or an array
+    #[serde(default = "default_jwt_claim")]
+    pub claim: String,
+    // Seconds of clock skew allowed on `exp` and `nbf`
+    #[serde(default = "default_jwt_leeway")]
+    pub leeway: u64,
+}
+
+fn default_jwt_algorithm() -> jsonwebtoken::Algorithm {
+    jsonwebtoken::Algorithm::RS256
+}
+
+fn default_jwt_claim() -> String {
+    "scope".to_string()
+}
+
+fn default_jwt_leeway() -> u64 {
+    60
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct MetricsConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_metrics_path")]
+    pub path: String,
+    // Bearer token required to scrape; open when unset
+    pub token: Option<String>,
+}
+
+fn default_metrics_path() -> String {
+    "/_metrics".to_string()
+}
+
+impl Default for MetricsConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            path: default_metrics_path(),
+            token: None,
+        }
+    }
+}
+
+fn default_drain_timeout() -> u64 {
+    30
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum ListenerConfig {
+    Tcp {
+        address: String,
+        #[serde(default)]
+        tls: bool,
+    },
+    Unix {
+        path: String,
+        // File permissions of the socket, e.g. `0o660`
+        mode: Option<u32>,
+    },
+    // An inherited, already listening socket. Without `fd`, every socket
+    // passed through systemd's LISTEN_FDS is used.
+    Fd {
+        fd: Option<i32>,
+        #[serde(default)]
+        tls: bool,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct TlsConfig {
+    // PEM certificate chain and private key, used when no `sni` entry matches
+    pub cert: String,
+    pub key: String,
+    #[serde(default)]
+    pub sni: Vec<SniCertificate>,
+    // Ask clients for a certificate, for buckets with `[auth.client_certs]`
+    #[serde(default)]
+    pub client_auth: bool,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct SniCertificate {
+    // Server names (or `*.example.com` wildcards) this certificate is for
+    pub hosts: Vec<String>,
+    pub cert: String,
+    pub key: String,
 }
 
 fn default_host() -> String {
@@ -29,14 +229,93 @@ impl Default for ServerConfig {
             host: default_host(),
             port: default_port(),
             buckets_dir: default_buckets_dir(),
+            compression: CompressionConfig::default(),
+            hosts: HashMap::new(),
+            tls: None,
+            listeners: Vec::new(),
+            drain_timeout: default_drain_timeout(),
+            metrics: MetricsConfig::default(),
+            access_log: AccessLogConfig::default(),
+            jwt: None,
+            trusted_proxies: Vec::new(),
+            ip: IpAccessConfig::default(),
+            rate_limit: RateLimitConfig::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompressionAlgorithm {
+    Br,
+    Zstd,
+    Gzip,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompressionConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_compression_algorithms")]
+    pub algorithms: Vec<CompressionAlgorithm>,
+    #[serde(default = "default_compression_mime_types")]
+    pub mime_types: Vec<String>,
+    #[serde(default = "default_compression_min_size")]
+    pub min_size: u64,
+}
+
+fn default_compression_algorithms() -> Vec<CompressionAlgorithm> {
+    vec![
+        CompressionAlgorithm::Br,
+        CompressionAlgorithm::Zstd,
+        CompressionAlgorithm::Gzip,
+    ]
+}
+
+fn default_compression_mime_types() -> Vec<String> {
+    [
+        "text/html",
+        "text/css",
+        "text/plain",
+        "text/javascript",
+        "application/javascript",
+        "application/json",
+        "application/xml",
+        "image/svg+xml",
+    ]
+    .into_iter()
+    .map(String::from)
+    .collect()
+}
+
+fn default_compression_min_size() -> u64 {
+    256
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            algorithms: default_compression_algorithms(),
+            mime_types: default_compression_mime_types(),
+            min_size: default_compression_min_size(),
         }
     }
 }
 
+#[derive(Debug, Default, Deserialize)]
+pub struct ErrorsConfig {
+    // HTML file used for error responses of buckets without their own page;
+    // `{{status}}` and `{{reason}}` are substituted.
+    pub template: Option<String>,
+}
+
 #[derive(Debug, Deserialize)]
 pub struct AppConfig {
     #[serde(default)]
     pub server: ServerConfig,
+    #[serde(default)]
+    pub errors: ErrorsConfig,
 }
 
 impl AppConfig {
@@ -48,6 +327,7 @@ impl AppConfig {
         } else {
             Ok(AppConfig {
                 server: ServerConfig::default(),
+                errors: ErrorsConfig::default(),
             })
         }
     }
@@ -55,12 +335,156 @@ impl AppConfig {
 
 #[derive(Debug, Deserialize)]
 pub struct BucketAuth {
-    pub token: String,
+    // Single token with every scope, kept for existing configs. Either the
+    // token itself or its hash, see `stathost hash-token`.
+    pub token: Option<String>,
+    pub token_hash: Option<String>,
+    #[serde(default)]
+    pub tokens: Vec<ScopedToken>,
+    // TLS client certificates accepted for uploads and deletes
+    pub client_certs: Option<ClientCertAuth>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct ClientCertAuth {
+    // PEM CA bundle, relative to the bucket directory
+    pub ca: Option<String>,
+    // Accepted leaf subjects, as a full DN (`O=Example, CN=ci`) or just the
+    // CN; any certificate issued by `ca` when empty
+    #[serde(default)]
+    pub subjects: Vec<String>,
+    // Pinned leaf certificates, `sha256:<hex>`
+    #[serde(default)]
+    pub fingerprints: Vec<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    Read,
+    List,
+    Write,
+    Delete,
+    // Implies every other scope
+    Admin,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScopedToken {
+    pub name: String,
+    pub token: Option<String>,
+    // `$argon2id$...` or `sha256:<hex>`, instead of `token`
+    pub token_hash: Option<String>,
+    pub scopes: Vec<Scope>,
+    // Path prefixes within the bucket the token is limited to, e.g.
+    // `releases/`; empty means the whole bucket
+    #[serde(default)]
+    pub paths: Vec<String>,
+}
+
+// Per-bucket overrides of the server-wide `[server.compression]` settings
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct BucketCompression {
+    pub enabled: Option<bool>,
+    pub mime_types: Option<Vec<String>>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+pub struct ServeConfig {
+    #[serde(default)]
+    pub autoindex: bool,
+    pub spa_fallback: Option<String>,
+    // Netlify-style `_redirects` file in the bucket whose rules are
+    // appended to `[[redirects]]`
+    pub redirects_file: Option<String>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct RedirectRule {
+    pub from: String,
+    pub to: String,
+    #[serde(default = "default_redirect_status")]
+    pub status: u16,
+}
+
+fn default_redirect_status() -> u16 {
+    301
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PresignConfig {
+    // HMAC key for presigned URLs; changing it revokes all of them
+    pub secret: String,
+    // Longest lifetime a presigned URL may be given, in seconds
+    #[serde(default = "default_presign_max_expires")]
+    pub max_expires: u64,
+}
+
+fn default_presign_max_expires() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+fn default_cors_methods() -> Vec<String> {
+    vec!["GET".to_string(), "HEAD".to_string()]
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CorsConfig {
+    // Exact origins such as `https://app.example.com`, or `*` for any
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_methods")]
+    pub allowed_methods: Vec<String>,
+    // Request headers a preflight may ask for; `*` allows any
+    #[serde(default)]
+    pub allowed_headers: Vec<String>,
+    #[serde(default)]
+    pub expose_headers: Vec<String>,
+    pub max_age: Option<u64>,
+    #[serde(default)]
+    pub allow_credentials: bool,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct HeaderRule {
+    #[serde(rename = "for")]
+    pub path: String,
+    pub values: HashMap<String, String>,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Visibility {
+    #[default]
+    Public,
+    // Reads need a token with the `read` scope
+    Private,
 }
 
 #[derive(Debug, Deserialize)]
 pub struct BucketConfig {
     pub auth: BucketAuth,
+    #[serde(default)]
+    pub visibility: Visibility,
+    // Host names (or `*.example.com` wildcards) that serve this bucket at `/`
+    #[serde(default)]
+    pub domains: Vec<String>,
+    #[serde(default)]
+    pub serve: ServeConfig,
+    #[serde(default)]
+    pub redirects: Vec<RedirectRule>,
+    #[serde(default)]
+    pub headers: Vec<HeaderRule>,
+    #[serde(default)]
+    pub compression: BucketCompression,
+    pub cors: Option<CorsConfig>,
+    pub presign: Option<PresignConfig>,
+    #[serde(default)]
+    pub ip: IpAccessConfig,
+    // Status code -> error page in the bucket, e.g. `404 = "errors/missing.html"`.
+    // Codes without an entry use `<code>.html` if present.
+    #[serde(default)]
+    pub errors: HashMap<String, String>,
 }
 
 impl BucketConfig {
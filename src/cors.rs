@@ -0,0 +1,219 @@
+use axum::{
+    body::Body,
+    extract::{Extension, Path, State},
+    http::{HeaderMap, HeaderName, HeaderValue, Method, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+use crate::bucket::{Bucket, BucketManager, LoadedBucket};
+use crate::config::CorsConfig;
+
+const FILE_METHODS: &str = "GET, HEAD, PUT, DELETE, OPTIONS";
+const BUCKET_ROOT_METHODS: &str = "GET, HEAD, OPTIONS";
+const META_METHODS: &str = "GET, OPTIONS";
+const PRESIGN_METHODS: &str = "POST, OPTIONS";
+
+pub fn validate(config: &CorsConfig) -> Result<(), String> {
+    // Credentialed requests carry tokens and cached Basic credentials, so
+    // letting any site make them would expose private buckets.
+    if config.allow_credentials && is_wildcard(&config.allowed_origins) {
+        return Err("CORS allow_credentials needs explicit allowed_origins, not \"*\"".to_string());
+    }
+    for origin in &config.allowed_origins {
+        HeaderValue::from_str(origin).map_err(|_| format!("invalid CORS origin {:?}", origin))?;
+    }
+    for method in &config.allowed_methods {
+        Method::from_bytes(method.as_bytes())
+            .map_err(|_| format!("invalid CORS method {:?}", method))?;
+    }
+    for name in config
+        .allowed_headers
+        .iter()
+        .chain(&config.expose_headers)
+        .filter(|name| *name != "*")
+    {
+        HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("invalid CORS header name {:?}", name))?;
+    }
+    Ok(())
+}
+
+fn is_wildcard(values: &[String]) -> bool {
+    values.iter().any(|v| v == "*")
+}
+
+fn allowed_origin(config: &CorsConfig, origin: &HeaderValue) -> Option<HeaderValue> {
+    if is_wildcard(&config.allowed_origins) {
+        Some(HeaderValue::from_static("*"))
+    } else {
+        let origin_str = origin.to_str().ok()?;
+        config
+            .allowed_origins
+            .iter()
+            .any(|allowed| allowed == origin_str)
+            .then(|| origin.clone())
+    }
+}
+
+fn varies_by_origin(config: &CorsConfig) -> bool {
+    !is_wildcard(&config.allowed_origins)
+}
+
+fn insert_origin(config: &CorsConfig, origin: HeaderValue, headers: &mut HeaderMap) {
+    headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    if config.allow_credentials {
+        headers.insert(
+            header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+            HeaderValue::from_static("true"),
+        );
+    }
+}
+
+// Adds CORS headers to an actual (non-preflight) response. Applied to error
+// responses as well, so scripts can read the status of a failed request.
+pub fn apply(bucket: &Bucket, origin: Option<&HeaderValue>, response: &mut Response) {
+    let Some(config) = &bucket.config().cors else {
+        return;
+    };
+    let headers = response.headers_mut();
+    if varies_by_origin(config) {
+        headers.append(header::VARY, HeaderValue::from_static("Origin"));
+    }
+
+    let Some(origin) = origin.and_then(|origin| allowed_origin(config, origin)) else {
+        return;
+    };
+    insert_origin(config, origin, headers);
+    if !config.expose_headers.is_empty()
+        && let Ok(value) = HeaderValue::from_str(&config.expose_headers.join(", "))
+    {
+        headers.insert(header::ACCESS_CONTROL_EXPOSE_HEADERS, value);
+    }
+}
+
+fn contains_ignore_case(values: &[String], value: &str) -> bool {
+    values.iter().any(|v| v.eq_ignore_ascii_case(value))
+}
+
+// Answers OPTIONS for a route supporting `methods`. Requests without
+// Access-Control-Request-Method are plain OPTIONS and just get `Allow`;
+// preflights the bucket's `[cors]` section does not permit get a 403.
+pub fn preflight(bucket: Option<&Bucket>, headers: &HeaderMap, methods: &'static str) -> Response {
+    let Some(bucket) = bucket else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let Some(requested_method) = headers.get(header::ACCESS_CONTROL_REQUEST_METHOD) else {
+        return match Response::builder()
+            .status(StatusCode::NO_CONTENT)
+            .header(header::ALLOW, methods)
+            .body(Body::empty())
+        {
+            Ok(response) => response,
+            Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+        };
+    };
+
+    let Some(config) = &bucket.config().cors else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+    let Some(origin) = headers
+        .get(header::ORIGIN)
+        .and_then(|origin| allowed_origin(config, origin))
+    else {
+        return StatusCode::FORBIDDEN.into_response();
+    };
+
+    let method_allowed = requested_method.to_str().is_ok_and(|method| {
+        contains_ignore_case(&config.allowed_methods, method)
+            && methods.split(", ").any(|m| m.eq_ignore_ascii_case(method))
+    });
+    if !method_allowed {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+
+    let requested_headers = headers
+        .get(header::ACCESS_CONTROL_REQUEST_HEADERS)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let any_header = is_wildcard(&config.allowed_headers);
+    let headers_allowed = requested_headers
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .all(|name| any_header || contains_ignore_case(&config.allowed_headers, name));
+    if !headers_allowed {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let allow_headers = if any_header {
+        requested_headers.to_string()
+    } else {
+        config.allowed_headers.join(", ")
+    };
+
+    let mut builder = Response::builder()
+        .status(StatusCode::NO_CONTENT)
+        .header(header::ALLOW, methods)
+        .header(
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            config.allowed_methods.join(", "),
+        );
+    if !allow_headers.is_empty() {
+        builder = builder.header(header::ACCESS_CONTROL_ALLOW_HEADERS, allow_headers);
+    }
+    if let Some(max_age) = config.max_age {
+        builder = builder.header(header::ACCESS_CONTROL_MAX_AGE, max_age);
+    }
+    if varies_by_origin(config) {
+        builder = builder.header(header::VARY, "Origin");
+    }
+
+    match builder.body(Body::empty()) {
+        Ok(mut response) => {
+            insert_origin(config, origin, response.headers_mut());
+            response
+        }
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+pub async fn preflight_file(
+    State(manager): State<Arc<BucketManager>>,
+    Path((bucket_name, _)): Path<(String, String)>,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    preflight(bucket.as_deref(), &headers, FILE_METHODS)
+}
+
+pub async fn preflight_bucket_root(
+    State(manager): State<Arc<BucketManager>>,
+    Path(bucket_name): Path<String>,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    preflight(bucket.as_deref(), &headers, BUCKET_ROOT_METHODS)
+}
+
+pub async fn preflight_meta(
+    State(manager): State<Arc<BucketManager>>,
+    Path(bucket_name): Path<String>,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    preflight(bucket.as_deref(), &headers, META_METHODS)
+}
+
+pub async fn preflight_presign(
+    State(manager): State<Arc<BucketManager>>,
+    Path(bucket_name): Path<String>,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    preflight(bucket.as_deref(), &headers, PRESIGN_METHODS)
+}
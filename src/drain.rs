@@ -0,0 +1,87 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+
+// Tracks uploads in flight, so shutdown can refuse new ones, wait for the
+// rest and report those it had to cut off.
+#[derive(Default)]
+pub struct Drain {
+    draining: AtomicBool,
+    next_id: AtomicU64,
+    uploads: Mutex<HashMap<u64, String>>,
+}
+
+pub struct UploadGuard<'a> {
+    drain: &'a Drain,
+    id: u64,
+}
+
+impl Drop for UploadGuard<'_> {
+    fn drop(&mut self) {
+        if let Ok(mut uploads) = self.drain.uploads.lock() {
+            uploads.remove(&self.id);
+        }
+    }
+}
+
+impl Drain {
+    pub fn start(&self) {
+        self.draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::SeqCst)
+    }
+
+    // Registers an upload for as long as the guard lives. Returns `None`
+    // once draining has started.
+    pub fn track_upload(&self, name: String) -> Option<UploadGuard<'_>> {
+        if self.is_draining() {
+            return None;
+        }
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.uploads.lock().ok()?.insert(id, name);
+        Some(UploadGuard { drain: self, id })
+    }
+
+    pub fn in_flight(&self) -> Vec<String> {
+        let mut uploads: Vec<String> = self
+            .uploads
+            .lock()
+            .map(|uploads| uploads.values().cloned().collect())
+            .unwrap_or_default();
+        uploads.sort();
+        uploads
+    }
+}
+
+// Resolves on SIGINT, or SIGTERM on Unix.
+pub async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            eprintln!("Failed to listen for Ctrl-C: {}", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        use tokio::signal::unix::{SignalKind, signal};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                terminate.recv().await;
+            }
+            Err(e) => {
+                eprintln!("Failed to install SIGTERM handler: {}", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {}
+        _ = terminate => {}
+    }
+}
@@ -0,0 +1,85 @@
+use axum::http::{HeaderMap, header};
+use std::path::{Path, PathBuf};
+
+// Precompressed sidecar files, in order of preference when the client
+// accepts several with equal quality.
+pub const PRECOMPRESSED: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+
+pub struct Variant {
+    pub path: PathBuf,
+    pub encoding: Option<&'static str>,
+    pub vary: bool,
+}
+
+pub fn sidecar_path(path: &Path, ext: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(ext);
+    PathBuf::from(name)
+}
+
+fn quality(accept: &str, coding: &str) -> f32 {
+    let mut wildcard = None;
+    for item in accept.split(',') {
+        let mut params = item.split(';');
+        let name = params.next().unwrap_or_default().trim();
+        let q = params
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .and_then(|q| q.trim().parse().ok())
+            .unwrap_or(1.0);
+        if name.eq_ignore_ascii_case(coding) {
+            return q;
+        }
+        if name == "*" {
+            wildcard = Some(q);
+        }
+    }
+    wildcard.unwrap_or(0.0)
+}
+
+// Picks the best precompressed sibling of `path` the client accepts, or the
+// file itself. Range requests always get the identity file so byte offsets
+// refer to the original content.
+pub async fn select_variant(path: &Path, headers: &HeaderMap) -> Variant {
+    let accept = headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|v| v.to_str().ok())
+        .unwrap_or_default();
+    let ranged = headers.contains_key(header::RANGE);
+
+    let identity = Variant {
+        path: path.to_path_buf(),
+        encoding: None,
+        vary: false,
+    };
+    // Sidecars are only served in place of a file that exists
+    if !tokio::fs::metadata(path).await.is_ok_and(|m| m.is_file()) {
+        return identity;
+    }
+
+    let mut vary = false;
+    let mut best: Option<(f32, PathBuf, &'static str)> = None;
+    for (encoding, ext) in PRECOMPRESSED {
+        let sidecar = sidecar_path(path, ext);
+        if !tokio::fs::metadata(&sidecar)
+            .await
+            .is_ok_and(|m| m.is_file())
+        {
+            continue;
+        }
+        vary = true;
+
+        let q = quality(accept, encoding);
+        if !ranged && q > 0.0 && best.as_ref().is_none_or(|(best_q, ..)| q > *best_q) {
+            best = Some((q, sidecar, encoding));
+        }
+    }
+
+    match best {
+        Some((_, sidecar, encoding)) => Variant {
+            path: sidecar,
+            encoding: Some(encoding),
+            vary,
+        },
+        None => Variant { vary, ..identity },
+    }
+}
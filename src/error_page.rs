@@ -0,0 +1,58 @@
+use axum::{
+    body::Body,
+    http::{HeaderValue, StatusCode, header},
+    response::Response,
+};
+
+use crate::bucket::{Bucket, BucketManager};
+
+async fn bucket_page(bucket: &Bucket, status: StatusCode) -> Option<(String, Vec<u8>)> {
+    let name = match bucket.config().errors.get(status.as_str()) {
+        Some(name) => name.clone(),
+        None => format!("{}.html", status.as_u16()),
+    };
+    let path = bucket.resolve_path(&name)?;
+    let content = tokio::fs::read(&path).await.ok()?;
+    let mime = mime_guess::from_path(&path)
+        .first_or_octet_stream()
+        .to_string();
+    Some((mime, content))
+}
+
+fn template_page(manager: &BucketManager, status: StatusCode) -> Option<(String, Vec<u8>)> {
+    let page = manager
+        .error_template()?
+        .replace("{{status}}", status.as_str())
+        .replace("{{reason}}", status.canonical_reason().unwrap_or_default());
+    Some(("text/html; charset=utf-8".to_string(), page.into_bytes()))
+}
+
+// Replaces the body of a 4xx/5xx response with the bucket's error page for
+// that status, or the server-wide template. Status and other headers are
+// kept; responses are returned unchanged when neither exists.
+pub async fn with_error_page(
+    manager: &BucketManager,
+    bucket: Option<&Bucket>,
+    response: Response,
+) -> Response {
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let page = match bucket {
+        Some(bucket) => bucket_page(bucket, status).await,
+        None => None,
+    };
+    let Some((mime, content)) = page.or_else(|| template_page(manager, status)) else {
+        return response;
+    };
+    let Ok(mime) = HeaderValue::from_str(&mime) else {
+        return response;
+    };
+
+    let (mut parts, _) = response.into_parts();
+    parts.headers.insert(header::CONTENT_TYPE, mime);
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(content))
+}
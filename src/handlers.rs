@@ -1,116 +1,356 @@
 use axum::{
     body::Body,
-    extract::{Path, Request, State},
-    http::{HeaderMap, StatusCode, header},
-    response::{IntoResponse, Response},
+    extract::{Extension, Path, RawQuery, Request, State},
+    http::{HeaderMap, StatusCode, Uri, header, response::Builder},
+    response::{IntoResponse, Redirect, Response},
 };
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicU64, Ordering};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncSeekExt, AsyncWriteExt, SeekFrom};
 use tokio_util::io::ReaderStream;
 
 use crate::{
-    auth::extract_token,
-    bucket::{BucketManager, TMP_SUFFIX},
+    auth, autoindex,
+    bucket::{Bucket, BucketManager, LoadedBucket, TMP_SUFFIX},
+    client_cert,
+    config::{Scope, Visibility},
+    cors,
+    encoding::{Variant, select_variant},
+    error_page::with_error_page,
+    presign::{self, PresignMethod},
+    range::{multipart_body, multipart_len, parse_ranges, resolve_ranges},
+    redirect::{self, Action},
+    response_headers,
 };
 
-enum RangeSpec {
-    FromTo(u64, u64),
-    From(u64),
-    Suffix(u64),
+fn etag_for(metadata: &std::fs::Metadata) -> String {
+    let mtime = metadata
+        .modified()
+        .ok()
+        .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+        .map(|d| d.as_nanos())
+        .unwrap_or(0);
+    format!("\"{:x}-{:x}\"", metadata.len(), mtime)
+}
+
+// HTTP dates have one-second resolution, so comparisons against
+// If-Modified-Since must ignore the sub-second part of the mtime.
+fn truncate_to_secs(time: SystemTime) -> SystemTime {
+    time.duration_since(UNIX_EPOCH)
+        .map(|d| UNIX_EPOCH + Duration::from_secs(d.as_secs()))
+        .unwrap_or(time)
+}
+
+fn weak_eq(a: &str, b: &str) -> bool {
+    a.trim_start_matches("W/") == b.trim_start_matches("W/")
+}
+
+fn is_not_modified(headers: &HeaderMap, etag: &str, modified: Option<SystemTime>) -> bool {
+    if let Some(value) = headers
+        .get(header::IF_NONE_MATCH)
+        .and_then(|v| v.to_str().ok())
+    {
+        return value
+            .split(',')
+            .map(str::trim)
+            .any(|candidate| candidate == "*" || weak_eq(candidate, etag));
+    }
+
+    let since = headers
+        .get(header::IF_MODIFIED_SINCE)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| httpdate::parse_http_date(v).ok());
+
+    match (modified, since) {
+        (Some(modified), Some(since)) => truncate_to_secs(modified) <= since,
+        _ => false,
+    }
+}
+
+// If-Range needs a strong match: either the exact ETag or the exact
+// Last-Modified date. Anything else means the client holds a different
+// version and must get the full body instead of a splice.
+fn if_range_matches(headers: &HeaderMap, etag: &str, last_modified: Option<&str>) -> bool {
+    let Some(value) = headers.get(header::IF_RANGE).and_then(|v| v.to_str().ok()) else {
+        return true;
+    };
+    let value = value.trim();
+    if value.starts_with("W/") {
+        false
+    } else if value.starts_with('"') {
+        value == etag
+    } else {
+        last_modified == Some(value)
+    }
+}
+
+fn with_validators(builder: Builder, etag: &str, last_modified: Option<&str>) -> Builder {
+    let builder = builder.header(header::ETAG, etag);
+    match last_modified {
+        Some(value) => builder.header(header::LAST_MODIFIED, value),
+        None => builder,
+    }
 }
 
-fn parse_range(value: &str) -> Option<RangeSpec> {
-    let spec = value.strip_prefix("bytes=")?.trim();
-    if spec.contains(',') {
+fn with_variant(builder: Builder, variant: &Variant) -> Builder {
+    let builder = match variant.encoding {
+        Some(encoding) => builder.header(header::CONTENT_ENCODING, encoding),
+        None => builder,
+    };
+    if variant.vary {
+        builder.header(header::VARY, "Accept-Encoding")
+    } else {
+        builder
+    }
+}
+
+fn not_modified(etag: &str, last_modified: Option<&str>, variant: &Variant) -> Response {
+    match with_variant(
+        with_validators(Response::builder(), etag, last_modified),
+        variant,
+    )
+    .status(StatusCode::NOT_MODIFIED)
+    .body(Body::empty())
+    {
+        Ok(response) => response,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+enum Target {
+    File(PathBuf),
+    Listing(String),
+    AddSlash(String),
+}
+
+// Paths whose last segment has an extension are treated as assets and get
+// a real 404; anything else is a client-side route of the single-page app.
+fn spa_fallback(bucket: &Bucket, file_path: &str) -> Option<PathBuf> {
+    let fallback = bucket.config().serve.spa_fallback.as_deref()?;
+    let last = file_path.trim_end_matches('/').rsplit('/').next()?;
+    if last.contains('.') {
         return None;
     }
-    let (start, end) = spec.split_once('-')?;
-    match (start.is_empty(), end.is_empty()) {
-        (false, false) => {
-            let start: u64 = start.parse().ok()?;
-            let end: u64 = end.parse().ok()?;
-            if start > end {
-                return None;
-            }
-            Some(RangeSpec::FromTo(start, end))
+    bucket.resolve_path(fallback)
+}
+
+fn resolve_target(bucket: &Bucket, file_path: &str) -> Option<Target> {
+    let autoindex = bucket.config().serve.autoindex;
+
+    if file_path.is_empty() || file_path.ends_with('/') {
+        let path = bucket.resolve_path(&format!("{}index.html", file_path))?;
+        if path.is_file() {
+            return Some(Target::File(path));
+        }
+        if autoindex && path.parent().is_some_and(|dir| dir.is_dir()) {
+            return Some(Target::Listing(file_path.to_string()));
         }
-        (false, true) => Some(RangeSpec::From(start.parse().ok()?)),
-        (true, false) => Some(RangeSpec::Suffix(end.parse().ok()?)),
-        (true, true) => None,
+        let fallback = spa_fallback(bucket, file_path).unwrap_or(path);
+        return Some(Target::File(fallback));
     }
+
+    let path = bucket.resolve_path(file_path)?;
+    if path.is_file() {
+        return Some(Target::File(path));
+    }
+    if autoindex && path.is_dir() {
+        // Relative redirect so it works however the bucket is mounted
+        let name = file_path.rsplit('/').next().unwrap_or(file_path);
+        return Some(Target::AddSlash(format!(
+            "{}/",
+            autoindex::encode_segment(name)
+        )));
+    }
+    let fallback = spa_fallback(bucket, file_path).unwrap_or(path);
+    Some(Target::File(fallback))
 }
 
-fn resolve_range(spec: RangeSpec, total: u64) -> Option<(u64, u64)> {
-    match spec {
-        RangeSpec::FromTo(start, end) => (start < total).then(|| (start, end.min(total - 1))),
-        RangeSpec::From(start) => (start < total).then(|| (start, total - 1)),
-        RangeSpec::Suffix(n) => (n > 0 && total > 0).then(|| (total.saturating_sub(n), total - 1)),
+fn redirect_response(status: StatusCode, base: &str, to: &str, query: Option<&str>) -> Response {
+    let mut location = if to.starts_with('/') {
+        format!("{}{}", base, to.trim_start_matches('/'))
+    } else {
+        to.to_string()
+    };
+    if let Some(query) = query.filter(|_| !location.contains('?')) {
+        location.push('?');
+        location.push_str(query);
+    }
+
+    match Response::builder()
+        .status(status)
+        .header(header::LOCATION, location)
+        .body(Body::empty())
+    {
+        Ok(response) => response,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
 
-pub async fn serve_file(
-    State(manager): State<Arc<BucketManager>>,
-    Path((bucket_name, file_path)): Path<(String, String)>,
-    headers: HeaderMap,
+// Shared GET/HEAD path for a bucket mounted at `base` (`/{bucket}/`, or `/`
+// for the root index and virtual hosts). Redirect rules run first and see
+// the path relative to the bucket.
+pub async fn serve_from_bucket(
+    manager: &BucketManager,
+    bucket: Option<&Bucket>,
+    base: &str,
+    file_path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    head: bool,
 ) -> Response {
-    let Some(bucket) = manager.get_bucket(&bucket_name) else {
-        return StatusCode::NOT_FOUND.into_response();
+    let Some(bucket) = bucket else {
+        return with_error_page(manager, None, StatusCode::NOT_FOUND.into_response()).await;
     };
+    let bucket_name = bucket.name();
+
+    let private = bucket.config().visibility == Visibility::Private;
+    if private {
+        // Presigned URLs stand in for a token; a bad or expired one is not
+        // answered with a login prompt.
+        let refused =
+            match presign::check(bucket, &bucket_name, PresignMethod::Get, file_path, query) {
+                Some(result) => result.err().map(IntoResponse::into_response),
+                None => auth::authorize(bucket, headers, Scope::Read, Some(file_path))
+                    .await
+                    .err()
+                    .map(|rejection| rejection.challenge(&bucket_name)),
+            };
+        if let Some(mut response) = refused {
+            cors::apply(bucket, headers.get(header::ORIGIN), &mut response);
+            return response;
+        }
+    }
 
-    let file_path = if file_path.is_empty() || file_path.ends_with('/') {
-        format!("{}index.html", file_path)
-    } else {
-        file_path
+    let file_path = match redirect::evaluate(bucket.redirects(), file_path) {
+        Some(Action::Redirect(status, to)) => {
+            let mut response = redirect_response(status, base, &to, query);
+            cors::apply(bucket, headers.get(header::ORIGIN), &mut response);
+            if private {
+                response = auth::granted(response);
+            }
+            return response;
+        }
+        Some(Action::Rewrite(to)) => to.trim_start_matches('/').to_string(),
+        None => file_path.to_string(),
     };
 
-    let Some(path) = bucket.resolve_path(&file_path) else {
-        return StatusCode::NOT_FOUND.into_response();
+    let target = resolve_target(bucket, &file_path);
+    // Header rules match the file actually served, so `*.html` also covers
+    // directory indexes and SPA fallbacks.
+    let served_path = match &target {
+        Some(Target::File(path)) => bucket.relative_path(path),
+        _ => file_path,
     };
 
-    let Ok(mut file) = File::open(&path).await else {
+    let response = match target {
+        Some(Target::File(path)) if head => head_path(&path, headers).await,
+        Some(Target::File(path)) => serve_path(&path, headers).await,
+        Some(Target::Listing(dir)) => autoindex::render(bucket, &dir, query, headers).await,
+        Some(Target::AddSlash(location)) => Redirect::permanent(&location).into_response(),
+        None => StatusCode::NOT_FOUND.into_response(),
+    };
+    let mut response = with_error_page(manager, Some(bucket), response).await;
+    response_headers::apply(bucket.header_sets(), &served_path, &mut response);
+    cors::apply(bucket, headers.get(header::ORIGIN), &mut response);
+    if !head {
+        response
+            .extensions_mut()
+            .insert(bucket.config().compression.clone());
+    }
+    if private {
+        response = auth::granted(response);
+    }
+    response
+}
+
+pub async fn serve_file(
+    State(manager): State<Arc<BucketManager>>,
+    Path((bucket_name, file_path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    let base = format!("/{}/", bucket_name);
+    serve_from_bucket(
+        &manager,
+        bucket.as_deref(),
+        &base,
+        &file_path,
+        query.as_deref(),
+        &headers,
+        false,
+    )
+    .await
+}
+
+async fn serve_path(path: &std::path::Path, headers: &HeaderMap) -> Response {
+    let variant = select_variant(path, headers).await;
+
+    let Ok(mut file) = File::open(&variant.path).await else {
         return StatusCode::NOT_FOUND.into_response();
     };
 
     let Ok(metadata) = file.metadata().await else {
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     };
+    if !metadata.is_file() {
+        return StatusCode::NOT_FOUND.into_response();
+    }
     let total = metadata.len();
+    let etag = etag_for(&metadata);
+    let modified = metadata.modified().ok();
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    let last_modified = last_modified.as_deref();
+
+    if is_not_modified(headers, &etag, modified) {
+        return not_modified(&etag, last_modified, &variant);
+    }
 
-    let mime = mime_guess::from_path(&path)
+    let mime = mime_guess::from_path(path)
         .first_or_octet_stream()
         .to_string();
 
-    let range = headers
+    let ranges = headers
         .get(header::RANGE)
         .and_then(|v| v.to_str().ok())
-        .and_then(parse_range);
-
-    if let Some(spec) = range {
-        let Some((start, end)) = resolve_range(spec, total) else {
-            return match Response::builder()
-                .status(StatusCode::RANGE_NOT_SATISFIABLE)
-                .header(header::CONTENT_RANGE, format!("bytes */{}", total))
-                .header(header::ACCEPT_RANGES, "bytes")
-                .body(Body::empty())
+        .and_then(parse_ranges)
+        .filter(|_| if_range_matches(headers, &etag, last_modified));
+
+    if let Some(ranges) = ranges {
+        let ranges = resolve_ranges(&ranges, total);
+
+        if ranges.is_empty() {
+            return match with_variant(
+                with_validators(Response::builder(), &etag, last_modified),
+                &variant,
+            )
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", total))
+            .header(header::ACCEPT_RANGES, "bytes")
+            .body(Body::empty())
             {
                 Ok(response) => response,
                 Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
             };
-        };
-
-        if file.seek(SeekFrom::Start(start)).await.is_err() {
-            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
         }
 
-        let len = end - start + 1;
-        let stream = ReaderStream::new(file.take(len));
-        let body = Body::from_stream(stream);
+        if let [(start, end)] = ranges[..] {
+            if file.seek(SeekFrom::Start(start)).await.is_err() {
+                return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+            }
+
+            let len = end - start + 1;
+            let stream = ReaderStream::new(file.take(len));
+            let body = Body::from_stream(stream);
 
-        return match Response::builder()
+            return match with_variant(
+                with_validators(Response::builder(), &etag, last_modified),
+                &variant,
+            )
             .status(StatusCode::PARTIAL_CONTENT)
             .header(header::CONTENT_TYPE, mime)
             .header(header::CONTENT_LENGTH, len)
@@ -120,6 +360,28 @@ pub async fn serve_file(
             )
             .header(header::ACCEPT_RANGES, "bytes")
             .body(body)
+            {
+                Ok(response) => response,
+                Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+            };
+        }
+
+        let boundary = format!("stathost-{}", unique_id());
+        let len = multipart_len(&ranges, &boundary, &mime, total);
+        let body = multipart_body(file.into_std().await, ranges, &boundary, &mime, total);
+
+        return match with_variant(
+            with_validators(Response::builder(), &etag, last_modified),
+            &variant,
+        )
+        .status(StatusCode::PARTIAL_CONTENT)
+        .header(
+            header::CONTENT_TYPE,
+            format!("multipart/byteranges; boundary={}", boundary),
+        )
+        .header(header::CONTENT_LENGTH, len)
+        .header(header::ACCEPT_RANGES, "bytes")
+        .body(body)
         {
             Ok(response) => response,
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
@@ -129,50 +391,196 @@ pub async fn serve_file(
     let stream = ReaderStream::new(file);
     let body = Body::from_stream(stream);
 
-    match Response::builder()
-        .status(StatusCode::OK)
-        .header(header::CONTENT_TYPE, mime)
-        .header(header::CONTENT_LENGTH, total)
-        .header(header::ACCEPT_RANGES, "bytes")
-        .body(body)
+    match with_variant(
+        with_validators(Response::builder(), &etag, last_modified),
+        &variant,
+    )
+    .status(StatusCode::OK)
+    .header(header::CONTENT_TYPE, mime)
+    .header(header::CONTENT_LENGTH, total)
+    .header(header::ACCEPT_RANGES, "bytes")
+    .body(body)
     {
         Ok(response) => response,
         Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
     }
 }
 
+pub async fn head_file(
+    State(manager): State<Arc<BucketManager>>,
+    Path((bucket_name, file_path)): Path<(String, String)>,
+    RawQuery(query): RawQuery,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    let base = format!("/{}/", bucket_name);
+    serve_from_bucket(
+        &manager,
+        bucket.as_deref(),
+        &base,
+        &file_path,
+        query.as_deref(),
+        &headers,
+        true,
+    )
+    .await
+}
+
+// Answers HEAD from file metadata alone; the file is never opened.
+async fn head_path(path: &std::path::Path, headers: &HeaderMap) -> Response {
+    let variant = select_variant(path, headers).await;
+
+    let metadata = match tokio::fs::metadata(&variant.path).await {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return StatusCode::NOT_FOUND.into_response(),
+    };
+    let etag = etag_for(&metadata);
+    let modified = metadata.modified().ok();
+    let last_modified = modified.map(httpdate::fmt_http_date);
+    let last_modified = last_modified.as_deref();
+
+    if is_not_modified(headers, &etag, modified) {
+        return not_modified(&etag, last_modified, &variant);
+    }
+
+    let mime = mime_guess::from_path(path)
+        .first_or_octet_stream()
+        .to_string();
+
+    match with_variant(
+        with_validators(Response::builder(), &etag, last_modified),
+        &variant,
+    )
+    .status(StatusCode::OK)
+    .header(header::CONTENT_TYPE, mime)
+    .header(header::CONTENT_LENGTH, metadata.len())
+    .header(header::ACCEPT_RANGES, "bytes")
+    .body(Body::empty())
+    {
+        Ok(response) => response,
+        Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+    }
+}
+
+// `/{bucket}` is redirected to `/{bucket}/`, so relative links in its index
+// or listing resolve inside the bucket
+fn add_bucket_slash(manager: &BucketManager, bucket_name: &str, uri: &Uri) -> Option<Response> {
+    if uri.path().ends_with('/') || !manager.has_bucket(bucket_name) {
+        return None;
+    }
+    let mut location = format!("{}/", autoindex::encode_segment(bucket_name));
+    if let Some(query) = uri.query() {
+        location.push('?');
+        location.push_str(query);
+    }
+    Some(Redirect::permanent(&location).into_response())
+}
+
 pub async fn serve_bucket_root(
     State(manager): State<Arc<BucketManager>>,
     Path(bucket_name): Path<String>,
+    RawQuery(query): RawQuery,
+    uri: Uri,
+    loaded: Option<Extension<LoadedBucket>>,
     headers: HeaderMap,
 ) -> Response {
-    serve_file(State(manager), Path((bucket_name, String::new())), headers).await
+    if let Some(response) = add_bucket_slash(&manager, &bucket_name, &uri) {
+        return response;
+    }
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    let base = format!("/{}/", bucket_name);
+    serve_from_bucket(
+        &manager,
+        bucket.as_deref(),
+        &base,
+        "",
+        query.as_deref(),
+        &headers,
+        false,
+    )
+    .await
+}
+
+pub async fn head_bucket_root(
+    State(manager): State<Arc<BucketManager>>,
+    Path(bucket_name): Path<String>,
+    RawQuery(query): RawQuery,
+    uri: Uri,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(response) = add_bucket_slash(&manager, &bucket_name, &uri) {
+        return response;
+    }
+    let bucket = manager.loaded_bucket(loaded.as_deref(), &bucket_name);
+    let base = format!("/{}/", bucket_name);
+    serve_from_bucket(
+        &manager,
+        bucket.as_deref(),
+        &base,
+        "",
+        query.as_deref(),
+        &headers,
+        true,
+    )
+    .await
 }
 
 pub async fn serve_root_index(
     State(manager): State<Arc<BucketManager>>,
+    RawQuery(query): RawQuery,
+    loaded: Option<Extension<LoadedBucket>>,
     headers: HeaderMap,
 ) -> Response {
-    serve_file(
-        State(manager),
-        Path(("index".to_string(), String::new())),
-        headers,
+    let bucket = manager.loaded_bucket(loaded.as_deref(), "index");
+    serve_from_bucket(
+        &manager,
+        bucket.as_deref(),
+        "/",
+        "",
+        query.as_deref(),
+        &headers,
+        false,
     )
     .await
 }
 
-fn temp_path(path: &std::path::Path) -> PathBuf {
+pub async fn head_root_index(
+    State(manager): State<Arc<BucketManager>>,
+    RawQuery(query): RawQuery,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+) -> Response {
+    let bucket = manager.loaded_bucket(loaded.as_deref(), "index");
+    serve_from_bucket(
+        &manager,
+        bucket.as_deref(),
+        "/",
+        "",
+        query.as_deref(),
+        &headers,
+        true,
+    )
+    .await
+}
+
+pub fn unique_id() -> String {
     static COUNTER: AtomicU64 = AtomicU64::new(0);
     let nanos = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .map(|d| d.as_nanos())
         .unwrap_or(0);
     let unique = COUNTER.fetch_add(1, Ordering::Relaxed);
+    format!("{:x}-{:x}", nanos, unique)
+}
+
+fn temp_path(path: &std::path::Path) -> PathBuf {
     let name = path
         .file_name()
         .map(|n| n.to_string_lossy().into_owned())
         .unwrap_or_default();
-    path.with_file_name(format!("{}.{:x}-{:x}{}", name, nanos, unique, TMP_SUFFIX))
+    path.with_file_name(format!("{}.{}{}", name, unique_id(), TMP_SUFFIX))
 }
 
 // Removes the temp file even if the handler future is dropped mid-upload
@@ -230,19 +638,46 @@ pub async fn upload_file(
     Path((bucket_name, file_path)): Path<(String, String)>,
     request: Request,
 ) -> Response {
-    let Some(bucket) = manager.get_bucket(&bucket_name) else {
+    let loaded = request.extensions().get::<LoadedBucket>();
+    let Some(bucket) = manager.loaded_bucket(loaded, &bucket_name) else {
         return StatusCode::NOT_FOUND.into_response();
     };
 
-    let Some(token) = extract_token(request.headers()) else {
-        return StatusCode::UNAUTHORIZED.into_response();
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    let name = format!("{}/{}", bucket_name, file_path);
+    let query = request.uri().query();
+    let authorized =
+        match presign::check(&bucket, &bucket_name, PresignMethod::Put, &file_path, query) {
+            Some(result) => result,
+            None if client_cert::accepted(&bucket, request.extensions()) => Ok(()),
+            None => auth::authorize(&bucket, request.headers(), Scope::Write, Some(&file_path))
+                .await
+                .map(|_| ()),
+        };
+    let mut response = match authorized {
+        Ok(()) => auth::granted(match manager.drain().track_upload(name) {
+            Some(_upload) => upload(&bucket, &file_path, request).await,
+            None => shutting_down(),
+        }),
+        Err(rejection) => rejection.into_response(),
     };
+    cors::apply(&bucket, origin.as_ref(), &mut response);
+    response
+}
 
-    if !bucket.validate_token(token) {
-        return StatusCode::FORBIDDEN.into_response();
-    }
+// New uploads are refused while the server drains for a shutdown; a restart
+// usually completes within a few seconds.
+fn shutting_down() -> Response {
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "5")],
+        "Server is shutting down",
+    )
+        .into_response()
+}
 
-    let Some(path) = bucket.resolve_path(&file_path) else {
+async fn upload(bucket: &Bucket, file_path: &str, request: Request) -> Response {
+    let Some(path) = bucket.resolve_path(file_path) else {
         return (StatusCode::BAD_REQUEST, "Invalid path").into_response();
     };
 
@@ -277,19 +712,29 @@ pub async fn delete_file(
     Path((bucket_name, file_path)): Path<(String, String)>,
     request: Request,
 ) -> Response {
-    let Some(bucket) = manager.get_bucket(&bucket_name) else {
+    let loaded = request.extensions().get::<LoadedBucket>();
+    let Some(bucket) = manager.loaded_bucket(loaded, &bucket_name) else {
         return StatusCode::NOT_FOUND.into_response();
     };
 
-    let Some(token) = extract_token(request.headers()) else {
-        return StatusCode::UNAUTHORIZED.into_response();
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    let authorized = if client_cert::accepted(&bucket, request.extensions()) {
+        Ok(())
+    } else {
+        auth::authorize(&bucket, request.headers(), Scope::Delete, Some(&file_path))
+            .await
+            .map(|_| ())
     };
+    let mut response = match authorized {
+        Ok(()) => auth::granted(delete(&bucket, &file_path).await),
+        Err(rejection) => rejection.into_response(),
+    };
+    cors::apply(&bucket, origin.as_ref(), &mut response);
+    response
+}
 
-    if !bucket.validate_token(token) {
-        return StatusCode::FORBIDDEN.into_response();
-    }
-
-    let Some(path) = bucket.resolve_path(&file_path) else {
+async fn delete(bucket: &Bucket, file_path: &str) -> Response {
+    let Some(path) = bucket.resolve_path(file_path) else {
         return (StatusCode::BAD_REQUEST, "Invalid path").into_response();
     };
 
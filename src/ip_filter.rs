@@ -0,0 +1,232 @@
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    http::{HeaderMap, Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::{
+    bucket::BucketManager,
+    config::{IpAccessConfig, IpRulesConfig},
+    listener::PeerAddr,
+    vhost,
+};
+
+// An address range, e.g. `10.0.0.0/8`; a bare address is a range of one
+#[derive(Debug, Clone, Copy)]
+pub struct Cidr {
+    network: IpAddr,
+    prefix: u32,
+}
+
+impl Cidr {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid address range {:?}", value);
+        let (address, prefix) = match value.split_once('/') {
+            Some((address, prefix)) => (address, Some(prefix)),
+            None => (value, None),
+        };
+        let network = address
+            .parse::<IpAddr>()
+            .map_err(|_| invalid())?
+            .to_canonical();
+        let bits = if network.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(prefix) => prefix.parse::<u32>().map_err(|_| invalid())?,
+            None => bits,
+        };
+        if prefix > bits {
+            return Err(invalid());
+        }
+        Ok(Self { network, prefix })
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.network, ip.to_canonical()) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = u32::MAX.checked_shl(32 - self.prefix).unwrap_or(0);
+                u32::from(network) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = u128::MAX.checked_shl(128 - self.prefix).unwrap_or(0);
+                u128::from(network) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+fn parse_all(values: &[String]) -> Result<Vec<Cidr>, String> {
+    values.iter().map(|value| Cidr::parse(value)).collect()
+}
+
+#[derive(Debug, Default)]
+struct IpRules {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+}
+
+impl IpRules {
+    fn compile(config: &IpRulesConfig) -> Result<Self, String> {
+        Ok(Self {
+            allow: parse_all(&config.allow)?,
+            deny: parse_all(&config.deny)?,
+        })
+    }
+
+    // `deny` wins over `allow`; a non-empty `allow` admits only its ranges,
+    // and nothing whose address is unknown.
+    fn permits(&self, ip: Option<IpAddr>) -> bool {
+        let Some(ip) = ip else {
+            return self.allow.is_empty();
+        };
+        !self.deny.iter().any(|cidr| cidr.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|cidr| cidr.contains(ip)))
+    }
+}
+
+// Allow/deny lists for reads (GET, HEAD, OPTIONS) and for everything else
+#[derive(Debug, Default)]
+pub struct IpAccess {
+    read: IpRules,
+    write: IpRules,
+}
+
+impl IpAccess {
+    pub fn compile(config: &IpAccessConfig) -> Result<Self, String> {
+        Ok(Self {
+            read: IpRules::compile(&config.read)?,
+            write: IpRules::compile(&config.write)?,
+        })
+    }
+
+    fn permits(&self, method: &Method, ip: Option<IpAddr>) -> bool {
+        if is_read(method) {
+            self.read.permits(ip)
+        } else {
+            self.write.permits(ip)
+        }
+    }
+}
+
+fn is_read(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS)
+}
+
+// Proxies whose forwarding headers are believed. `unix` stands for clients
+// of Unix socket listeners, which have no address of their own.
+#[derive(Debug, Default)]
+pub struct TrustedProxies {
+    ranges: Vec<Cidr>,
+    unix: bool,
+}
+
+impl TrustedProxies {
+    pub fn compile(values: &[String]) -> Result<Self, String> {
+        let mut proxies = Self::default();
+        for value in values {
+            if value == "unix" {
+                proxies.unix = true;
+            } else {
+                proxies.ranges.push(Cidr::parse(value)?);
+            }
+        }
+        Ok(proxies)
+    }
+
+    fn trusts(&self, ip: Option<IpAddr>) -> bool {
+        match ip {
+            Some(ip) => self.ranges.iter().any(|cidr| cidr.contains(ip)),
+            None => self.unix,
+        }
+    }
+}
+
+// A `Forwarded` node such as `192.0.2.60`, `"[2001:db8::1]:4711"` or
+// `"192.0.2.60:8080"`. `unknown` and obfuscated names give `None`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    if let Some(rest) = node.strip_prefix('[') {
+        return rest.split_once(']')?.0.parse().ok();
+    }
+    node.parse()
+        .ok()
+        .or_else(|| node.split_once(':')?.0.parse().ok())
+}
+
+// Client-to-proxy hops from `Forwarded`, or else `X-Forwarded-For`, the
+// nearest last. `None` entries could not be parsed.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let values = |name| {
+        headers
+            .get_all(name)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(str::to_string)
+            .collect::<Vec<_>>()
+    };
+    let forwarded = values(header::FORWARDED);
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (key, value) = pair.trim().split_once('=')?;
+                    key.eq_ignore_ascii_case("for").then(|| parse_node(value))
+                })?
+            })
+            .collect();
+    }
+    values(header::HeaderName::from_static("x-forwarded-for"))
+        .iter()
+        .map(|node| parse_node(node))
+        .collect()
+}
+
+// The client's address: the connection's peer, unless that is a trusted
+// proxy, in which case the forwarding headers are walked back past every
+// trusted proxy. A hop that cannot be parsed leaves the client unknown.
+pub fn client_ip(manager: &BucketManager, request: &Request) -> Option<IpAddr> {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<PeerAddr>>()
+        .and_then(|ConnectInfo(PeerAddr(addr))| addr.map(|addr| addr.ip().to_canonical()));
+    let proxies = manager.trusted_proxies();
+    if !proxies.trusts(peer) {
+        return peer;
+    }
+
+    let mut client = peer;
+    for hop in forwarded_hops(request.headers()).into_iter().rev() {
+        client = Some(hop?.to_canonical());
+        if !proxies.trusts(client) {
+            break;
+        }
+    }
+    client
+}
+
+// Applies the server-wide lists, then those of the request's bucket, before
+// any handler runs.
+pub async fn filter_ip(
+    State(manager): State<Arc<BucketManager>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let ip = client_ip(&manager, &request);
+    if !manager.ip_access().permits(request.method(), ip) {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    let permitted = vhost::load_request_bucket(&manager, &mut request).is_none_or(|loaded| {
+        loaded
+            .bucket()
+            .is_none_or(|bucket| bucket.ip_access().permits(request.method(), ip))
+    });
+    if !permitted {
+        return StatusCode::FORBIDDEN.into_response();
+    }
+    next.run(request).await
+}
@@ -0,0 +1,166 @@
+use jsonwebtoken::{
+    Algorithm, DecodingKey, Validation,
+    jwk::{AlgorithmParameters, EllipticCurve, JwkSet},
+};
+use std::str::FromStr;
+
+use crate::config::{JwtConfig, Scope};
+
+const SCOPE_PREFIX: &str = "stathost:";
+
+struct Key {
+    kid: Option<String>,
+    key: DecodingKey,
+    // Algorithms a token signed with this key may use; the token's own
+    // `alg` header is only trusted within these
+    algorithms: Vec<Algorithm>,
+}
+
+pub struct JwtVerifier {
+    keys: Vec<Key>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    claim: String,
+    leeway: u64,
+}
+
+fn algorithms_for(params: &AlgorithmParameters) -> Vec<Algorithm> {
+    use Algorithm::*;
+    match params {
+        AlgorithmParameters::RSA(_) => vec![RS256, RS384, RS512, PS256, PS384, PS512],
+        AlgorithmParameters::EllipticCurve(ec) => match ec.curve {
+            EllipticCurve::P256 => vec![ES256],
+            EllipticCurve::P384 => vec![ES384],
+            _ => Vec::new(),
+        },
+        AlgorithmParameters::OctetKey(_) => vec![HS256, HS384, HS512],
+        AlgorithmParameters::OctetKeyPair(_) => vec![EdDSA],
+    }
+}
+
+fn load_jwks(path: &str) -> Result<Vec<Key>, Box<dyn std::error::Error>> {
+    let jwks: JwkSet = serde_json::from_str(&std::fs::read_to_string(path)?)?;
+    let mut keys = Vec::new();
+    for jwk in &jwks.keys {
+        let algorithms = match jwk.common.key_algorithm {
+            Some(alg) => vec![Algorithm::from_str(&alg.to_string())?],
+            None => algorithms_for(&jwk.algorithm),
+        };
+        if algorithms.is_empty() {
+            return Err(format!("{}: unsupported key type", path).into());
+        }
+        keys.push(Key {
+            kid: jwk.common.key_id.clone(),
+            key: DecodingKey::from_jwk(jwk)?,
+            algorithms,
+        });
+    }
+    if keys.is_empty() {
+        return Err(format!("{}: no keys", path).into());
+    }
+    Ok(keys)
+}
+
+fn load_pem(path: &str, algorithm: Algorithm) -> Result<Key, Box<dyn std::error::Error>> {
+    use Algorithm::*;
+    let pem = std::fs::read(path)?;
+    let key = match algorithm {
+        RS256 | RS384 | RS512 | PS256 | PS384 | PS512 => DecodingKey::from_rsa_pem(&pem)?,
+        ES256 | ES384 => DecodingKey::from_ec_pem(&pem)?,
+        EdDSA => DecodingKey::from_ed_pem(&pem)?,
+        HS256 | HS384 | HS512 => {
+            return Err("HMAC keys must be given as `oct` keys in a JWKS file".into());
+        }
+    };
+    Ok(Key {
+        kid: None,
+        key,
+        algorithms: vec![algorithm],
+    })
+}
+
+impl JwtVerifier {
+    pub fn load(config: &JwtConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let keys = match (&config.jwks, &config.pem) {
+            (Some(jwks), None) => load_jwks(jwks)?,
+            (None, Some(pem)) => vec![load_pem(pem, config.algorithm)?],
+            _ => return Err("jwt needs exactly one of jwks or pem".into()),
+        };
+        Ok(Self {
+            keys,
+            issuer: config.issuer.clone(),
+            audience: config.audience.clone(),
+            claim: config.claim.clone(),
+            leeway: config.leeway,
+        })
+    }
+
+    fn validation(&self, algorithm: Algorithm) -> Validation {
+        let mut validation = Validation::new(algorithm);
+        validation.leeway = self.leeway;
+        validation.validate_nbf = true;
+        let mut required = vec!["exp"];
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+            required.push("iss");
+        }
+        match &self.audience {
+            Some(audience) => {
+                validation.set_audience(&[audience]);
+                required.push("aud");
+            }
+            None => validation.validate_aud = false,
+        }
+        validation.set_required_spec_claims(&required);
+        validation
+    }
+
+    // Verifies the signature, expiry, issuer and audience of `token` and
+    // returns its subject and the scopes it grants on `bucket`. `None` for
+    // anything that is not a valid JWT.
+    pub fn verify(&self, token: &str, bucket: &str) -> Option<(String, Vec<Scope>)> {
+        let header = jsonwebtoken::decode_header(token).ok()?;
+        let claims = self
+            .keys
+            .iter()
+            .filter(|key| header.kid.is_none() || key.kid.is_none() || key.kid == header.kid)
+            .filter(|key| key.algorithms.contains(&header.alg))
+            .find_map(|key| {
+                jsonwebtoken::decode::<serde_json::Value>(
+                    token,
+                    &key.key,
+                    &self.validation(header.alg),
+                )
+                .ok()
+            })?
+            .claims;
+
+        let subject = claims
+            .get("sub")
+            .and_then(|sub| sub.as_str())
+            .unwrap_or("jwt")
+            .to_string();
+        let granted: Vec<&str> = match claims.get(&self.claim) {
+            // OAuth-style space separated `scope`
+            Some(serde_json::Value::String(value)) => value.split_whitespace().collect(),
+            Some(serde_json::Value::Array(values)) => {
+                values.iter().filter_map(|value| value.as_str()).collect()
+            }
+            _ => Vec::new(),
+        };
+        let scopes = granted
+            .into_iter()
+            .filter_map(|value| parse_scope(value, bucket))
+            .collect();
+        Some((subject, scopes))
+    }
+}
+
+// `stathost:<bucket>:<scope>`, with `*` as the bucket for all buckets
+fn parse_scope(value: &str, bucket: &str) -> Option<Scope> {
+    let (name, scope) = value.strip_prefix(SCOPE_PREFIX)?.split_once(':')?;
+    if name != bucket && name != "*" {
+        return None;
+    }
+    serde_json::from_value(serde_json::Value::String(scope.to_string())).ok()
+}
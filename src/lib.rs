@@ -1,9 +1,46 @@
+mod access_log;
 mod auth;
+mod autoindex;
 mod bucket;
+mod client_cert;
+mod compression;
 pub mod config;
+mod cors;
+mod drain;
+mod encoding;
+mod error_page;
 mod handlers;
+mod ip_filter;
+mod jwt;
+mod listener;
 mod meta;
+mod metrics;
+mod presign;
+mod range;
+mod rate_limit;
+mod redirect;
+mod response_headers;
+mod tls;
+mod vhost;
 
+pub use access_log::access_log;
+pub use auth::hash_token;
 pub use bucket::{BucketManager, FileEntry, cleanup_temp_files};
-pub use handlers::{delete_file, serve_bucket_root, serve_file, serve_root_index, upload_file};
+pub use compression::compression_layer;
+pub use cors::{preflight_bucket_root, preflight_file, preflight_meta, preflight_presign};
+pub use drain::shutdown_signal;
+pub use handlers::{
+    delete_file, head_bucket_root, head_file, head_root_index, serve_bucket_root, serve_file,
+    serve_root_index, upload_file,
+};
+pub use ip_filter::{IpAccess, TrustedProxies, filter_ip};
+pub use jwt::JwtVerifier;
+pub use listener::{BoundListener, PeerAddr, bind_listeners};
 pub use meta::{list_files, openapi};
+pub use metrics::{serve_metrics, track_metrics};
+pub use presign::presign;
+pub use rate_limit::{RateLimiter, log_rate_limits, rate_limit};
+#[cfg(unix)]
+pub use tls::reload_on_sighup;
+pub use tls::{CertResolver, serve_tls, server_config};
+pub use vhost::route_host;
@@ -0,0 +1,247 @@
+use axum::{Router, extract::connect_info::Connected, serve::IncomingStream};
+use std::net::SocketAddr;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+#[cfg(unix)]
+use tokio::net::UnixListener;
+use tokio_util::sync::CancellationToken;
+
+use crate::config::{ListenerConfig, ServerConfig};
+use crate::tls::serve_tls;
+
+// Address of the connected client, available to handlers as
+// `ConnectInfo<PeerAddr>`. Unix socket clients have no IP address.
+#[derive(Debug, Clone, Copy)]
+pub struct PeerAddr(pub Option<SocketAddr>);
+
+impl From<SocketAddr> for PeerAddr {
+    fn from(addr: SocketAddr) -> Self {
+        Self(Some(addr))
+    }
+}
+
+#[cfg(unix)]
+impl From<tokio::net::unix::SocketAddr> for PeerAddr {
+    fn from(_: tokio::net::unix::SocketAddr) -> Self {
+        Self(None)
+    }
+}
+
+impl Connected<IncomingStream<'_, TcpListener>> for PeerAddr {
+    fn connect_info(stream: IncomingStream<'_, TcpListener>) -> Self {
+        (*stream.remote_addr()).into()
+    }
+}
+
+#[cfg(unix)]
+impl Connected<IncomingStream<'_, UnixListener>> for PeerAddr {
+    fn connect_info(_: IncomingStream<'_, UnixListener>) -> Self {
+        Self(None)
+    }
+}
+
+enum Socket {
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+pub struct BoundListener {
+    socket: Socket,
+    tls: bool,
+}
+
+impl BoundListener {
+    // e.g. `https://0.0.0.0:8443` or `unix:/run/stathost/upload.sock`
+    pub fn describe(&self) -> String {
+        let scheme = if self.tls { "https" } else { "http" };
+        match &self.socket {
+            Socket::Tcp(listener) => match listener.local_addr() {
+                Ok(addr) => format!("{}://{}", scheme, addr),
+                Err(_) => format!("{}://<unknown>", scheme),
+            },
+            #[cfg(unix)]
+            Socket::Unix(listener) => {
+                let path = listener
+                    .local_addr()
+                    .ok()
+                    .and_then(|addr| addr.as_pathname().map(|p| p.display().to_string()))
+                    .unwrap_or_default();
+                format!("unix:{}", path)
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        match &self.socket {
+            Socket::Tcp(listener) => listener.local_addr().ok(),
+            #[cfg(unix)]
+            Socket::Unix(_) => None,
+        }
+    }
+
+    // Serves until `shutdown` is cancelled and in-flight requests finish.
+    pub async fn serve(
+        self,
+        app: Router,
+        tls: Option<Arc<rustls::ServerConfig>>,
+        shutdown: CancellationToken,
+    ) -> std::io::Result<()> {
+        match (self.socket, tls.filter(|_| self.tls)) {
+            (Socket::Tcp(listener), Some(tls)) => {
+                serve_tls(listener, app, tls, shutdown).await;
+                Ok(())
+            }
+            (Socket::Tcp(listener), None) => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<PeerAddr>(),
+                )
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+            }
+            #[cfg(unix)]
+            (Socket::Unix(listener), Some(tls)) => {
+                serve_tls(listener, app, tls, shutdown).await;
+                Ok(())
+            }
+            #[cfg(unix)]
+            (Socket::Unix(listener), None) => {
+                axum::serve(
+                    listener,
+                    app.into_make_service_with_connect_info::<PeerAddr>(),
+                )
+                .with_graceful_shutdown(shutdown.cancelled_owned())
+                .await
+            }
+        }
+    }
+}
+
+// File descriptors passed by systemd socket activation, if they are meant
+// for this process.
+#[cfg(unix)]
+fn systemd_fds() -> Option<Vec<i32>> {
+    const LISTEN_FDS_START: i32 = 3;
+
+    let pid: u32 = std::env::var("LISTEN_PID").ok()?.parse().ok()?;
+    if pid != std::process::id() {
+        return None;
+    }
+    let count: i32 = std::env::var("LISTEN_FDS").ok()?.parse().ok()?;
+    Some((LISTEN_FDS_START..LISTEN_FDS_START + count).collect())
+}
+
+#[cfg(not(unix))]
+fn systemd_fds() -> Option<Vec<i32>> {
+    None
+}
+
+#[cfg(unix)]
+fn from_fd(fd: i32, tls: bool) -> Result<BoundListener, Box<dyn std::error::Error>> {
+    use std::os::fd::{FromRawFd, IntoRawFd};
+
+    if fd < 0 {
+        return Err("invalid file descriptor".into());
+    }
+
+    // SAFETY: the fd was handed to this process as a listening socket,
+    // either by systemd or explicitly through the config, and nothing else
+    // in the process owns it.
+    let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+    let socket = if tcp.local_addr().is_ok() {
+        tcp.set_nonblocking(true)?;
+        Socket::Tcp(TcpListener::from_std(tcp)?)
+    } else {
+        // SAFETY: ownership moves from `tcp`, which is consumed here.
+        let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+        unix.local_addr().map_err(|_| "not a TCP or Unix socket")?;
+        unix.set_nonblocking(true)?;
+        Socket::Unix(UnixListener::from_std(unix)?)
+    };
+    Ok(BoundListener { socket, tls })
+}
+
+#[cfg(not(unix))]
+fn from_fd(_fd: i32, _tls: bool) -> Result<BoundListener, Box<dyn std::error::Error>> {
+    Err("inherited sockets are only supported on Unix".into())
+}
+
+#[cfg(unix)]
+fn bind_unix(path: &str, mode: Option<u32>) -> Result<BoundListener, Box<dyn std::error::Error>> {
+    use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+    // A socket file left behind by a previous run would make bind fail
+    if std::fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(BoundListener {
+        socket: Socket::Unix(listener),
+        tls: false,
+    })
+}
+
+#[cfg(not(unix))]
+fn bind_unix(_path: &str, _mode: Option<u32>) -> Result<BoundListener, Box<dyn std::error::Error>> {
+    Err("Unix sockets are not supported on this platform".into())
+}
+
+// Binds every configured listener, failing on the first one that cannot be
+// bound.
+pub async fn bind_listeners(
+    config: &ServerConfig,
+) -> Result<Vec<BoundListener>, Box<dyn std::error::Error>> {
+    let default_tls = config.tls.is_some();
+    let default = if systemd_fds().is_some() {
+        ListenerConfig::Fd {
+            fd: None,
+            tls: default_tls,
+        }
+    } else {
+        ListenerConfig::Tcp {
+            address: format!("{}:{}", config.host, config.port),
+            tls: default_tls,
+        }
+    };
+    let listeners = if config.listeners.is_empty() {
+        std::slice::from_ref(&default)
+    } else {
+        &config.listeners[..]
+    };
+
+    let mut bound = Vec::new();
+    for listener in listeners {
+        match listener {
+            ListenerConfig::Tcp { address, tls } => {
+                let listener = TcpListener::bind(address)
+                    .await
+                    .map_err(|e| format!("{}: {}", address, e))?;
+                bound.push(BoundListener {
+                    socket: Socket::Tcp(listener),
+                    tls: *tls,
+                });
+            }
+            ListenerConfig::Unix { path, mode } => {
+                bound.push(bind_unix(path, *mode).map_err(|e| format!("{}: {}", path, e))?);
+            }
+            ListenerConfig::Fd { fd: Some(fd), tls } => {
+                bound.push(from_fd(*fd, *tls).map_err(|e| format!("fd {}: {}", fd, e))?);
+            }
+            ListenerConfig::Fd { fd: None, tls } => {
+                let fds = systemd_fds().ok_or("no sockets were passed by systemd (LISTEN_FDS)")?;
+                for fd in fds {
+                    bound.push(from_fd(fd, *tls).map_err(|e| format!("fd {}: {}", fd, e))?);
+                }
+            }
+        }
+    }
+
+    if bound.iter().any(|listener| listener.tls) && config.tls.is_none() {
+        return Err("a listener has tls = true, but [server.tls] is not configured".into());
+    }
+    Ok(bound)
+}
@@ -1,29 +1,50 @@
-use axum::{Router, extract::Request, middleware::Next, response::Response, routing::get};
+use axum::{
+    Router,
+    routing::{get, post},
+};
 use stathost::BucketManager;
 use stathost::config::AppConfig;
-use std::{path::PathBuf, sync::Arc, time::Instant};
+use std::{path::PathBuf, sync::Arc, time::Duration};
+use tokio_util::sync::CancellationToken;
 
-async fn access_log(request: Request, next: Next) -> Response {
-    let method = request.method().clone();
-    let uri = request.uri().clone();
-    let start = Instant::now();
-
-    let response = next.run(request).await;
-
-    let elapsed = start.elapsed();
-    println!(
-        "{} {} {} {}ms",
-        method,
-        uri,
-        response.status().as_u16(),
-        elapsed.as_millis()
-    );
+// `stathost hash-token [--sha256] [TOKEN]` prints the hash to put in
+// `token_hash`. The token is read from stdin when not given, which keeps it
+// out of the shell history.
+fn hash_token(args: &[String]) {
+    let sha256 = args.iter().any(|a| a == "--sha256");
+    let token = match args.iter().find(|a| !a.starts_with("--")) {
+        Some(token) => token.clone(),
+        None => {
+            let mut line = String::new();
+            if let Err(e) = std::io::stdin().read_line(&mut line) {
+                eprintln!("Failed to read token: {}", e);
+                std::process::exit(1);
+            }
+            line.trim_end_matches(['\r', '\n']).to_string()
+        }
+    };
+    if token.is_empty() {
+        eprintln!("Usage: stathost hash-token [--sha256] [TOKEN]");
+        std::process::exit(1);
+    }
 
-    response
+    match stathost::hash_token(&token, sha256) {
+        Ok(hash) => println!("{}", hash),
+        Err(e) => {
+            eprintln!("Failed to hash token: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
 
 #[tokio::main]
 async fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.get(1).is_some_and(|a| a == "hash-token") {
+        hash_token(&args[2..]);
+        return;
+    }
+
     let config_path = std::env::args()
         .skip_while(|a| a != "--config")
         .nth(1)
@@ -42,40 +63,211 @@ async fn main() {
         });
     }
 
-    if let Err(e) = stathost::cleanup_temp_files(&buckets_dir).await {
-        eprintln!("Failed to clean up stale temp files: {}", e);
-    }
+    let cleaned = match stathost::cleanup_temp_files(&buckets_dir).await {
+        Ok(cleaned) => cleaned,
+        Err(e) => {
+            eprintln!("Failed to clean up stale temp files: {}", e);
+            0
+        }
+    };
 
-    let manager = Arc::new(BucketManager::new(buckets_dir));
+    let mut manager =
+        BucketManager::new(buckets_dir.clone()).with_hosts(config.server.hosts.clone());
+    if let Some(path) = &config.errors.template {
+        let template = std::fs::read_to_string(path).unwrap_or_else(|e| {
+            eprintln!("Failed to read error template {}: {}", path, e);
+            std::process::exit(1);
+        });
+        manager = manager.with_error_template(template);
+    }
+    if let Some(token) = &config.server.metrics.token {
+        manager = manager.with_metrics_token(token.clone());
+    }
+    if let Some(jwt) = &config.server.jwt {
+        let verifier = stathost::JwtVerifier::load(jwt).unwrap_or_else(|e| {
+            eprintln!("Failed to load JWT keys: {}", e);
+            std::process::exit(1);
+        });
+        manager = manager.with_jwt(verifier);
+    }
+    let trusted_proxies = stathost::TrustedProxies::compile(&config.server.trusted_proxies)
+        .unwrap_or_else(|e| {
+            eprintln!("Invalid trusted_proxies: {}", e);
+            std::process::exit(1);
+        });
+    let ip_access = stathost::IpAccess::compile(&config.server.ip).unwrap_or_else(|e| {
+        eprintln!("Invalid [server.ip]: {}", e);
+        std::process::exit(1);
+    });
+    manager = manager
+        .with_trusted_proxies(trusted_proxies)
+        .with_ip_access(ip_access);
+    match stathost::RateLimiter::new(&config.server.rate_limit) {
+        Ok(Some(limiter)) => manager = manager.with_rate_limiter(limiter),
+        Ok(None) => {}
+        Err(e) => {
+            eprintln!("Invalid [server.rate_limit]: {}", e);
+            std::process::exit(1);
+        }
+    }
+    manager = manager.with_access_log_format(config.server.access_log.format);
+    manager.metrics().add_temp_files_cleaned(cleaned);
+    let manager = Arc::new(manager);
+    tokio::spawn(stathost::log_rate_limits(manager.clone()));
 
-    let app = Router::new()
-        .route("/", get(stathost::serve_root_index))
+    let mut app = Router::new()
+        .route(
+            "/",
+            get(stathost::serve_root_index).head(stathost::head_root_index),
+        )
         .route("/openapi.json", get(stathost::openapi))
-        .route("/{bucket}", get(stathost::serve_bucket_root))
-        .route("/{bucket}/", get(stathost::serve_bucket_root))
-        .route("/{bucket}/_meta/list", get(stathost::list_files))
+        .route(
+            "/{bucket}",
+            get(stathost::serve_bucket_root)
+                .head(stathost::head_bucket_root)
+                .options(stathost::preflight_bucket_root),
+        )
+        .route(
+            "/{bucket}/",
+            get(stathost::serve_bucket_root)
+                .head(stathost::head_bucket_root)
+                .options(stathost::preflight_bucket_root),
+        )
+        .route(
+            "/{bucket}/_meta/list",
+            get(stathost::list_files).options(stathost::preflight_meta),
+        )
+        .route(
+            "/{bucket}/_meta/presign",
+            post(stathost::presign).options(stathost::preflight_presign),
+        )
         .route(
             "/{bucket}/{*path}",
             get(stathost::serve_file)
+                .head(stathost::head_file)
                 .put(stathost::upload_file)
-                .delete(stathost::delete_file),
+                .delete(stathost::delete_file)
+                .options(stathost::preflight_file),
         )
-        .layer(axum::middleware::from_fn(access_log))
-        .with_state(manager);
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::route_host,
+        ));
+    // The metrics endpoint is not served from virtual hosts' buckets, but is
+    // subject to the server-wide address lists and rate limits.
+    let metrics = config.server.metrics.enabled;
+    if metrics {
+        if !config.server.metrics.path.starts_with('/') {
+            eprintln!(
+                "Invalid metrics path {:?}: must start with /",
+                config.server.metrics.path
+            );
+            std::process::exit(1);
+        }
+        app = app.route(&config.server.metrics.path, get(stathost::serve_metrics));
+    }
+    app = app
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::filter_ip,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::rate_limit,
+        ))
+        .layer(stathost::compression_layer(&config.server.compression));
+    if metrics {
+        app = app.layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::track_metrics,
+        ));
+    }
+    let app = app
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::access_log,
+        ))
+        .with_state(manager.clone());
 
-    let addr = format!("{}:{}", config.server.host, config.server.port);
-    println!("StatHost listening on {}", addr);
+    let tls = config.server.tls.clone().map(|tls| {
+        let resolver = stathost::CertResolver::load(tls)
+            .map(Arc::new)
+            .unwrap_or_else(|e| {
+                eprintln!("Failed to load TLS certificates: {}", e);
+                std::process::exit(1);
+            });
+        let server_config = stathost::server_config(resolver.clone()).unwrap_or_else(|e| {
+            eprintln!("Failed to configure TLS: {}", e);
+            std::process::exit(1);
+        });
+        (resolver, server_config)
+    });
 
-    let listener = match tokio::net::TcpListener::bind(&addr).await {
-        Ok(l) => l,
-        Err(e) => {
-            eprintln!("Failed to bind to {}: {}", addr, e);
+    let listeners = stathost::bind_listeners(&config.server)
+        .await
+        .unwrap_or_else(|e| {
+            eprintln!("Failed to bind: {}", e);
             std::process::exit(1);
+        });
+
+    let (_resolver, server_config) = tls.unzip();
+    // Installed even without TLS, since SIGHUP would otherwise end the process
+    #[cfg(unix)]
+    tokio::spawn(stathost::reload_on_sighup(_resolver));
+
+    let shutdown = CancellationToken::new();
+    let mut servers = tokio::task::JoinSet::new();
+    for listener in listeners {
+        println!("StatHost listening on {}", listener.describe());
+        servers.spawn(listener.serve(app.clone(), server_config.clone(), shutdown.clone()));
+    }
+
+    let servers_done = async move {
+        while let Some(result) = servers.join_next().await {
+            if let Ok(Err(e)) = result {
+                eprintln!("Server error: {}", e);
+                std::process::exit(1);
+            }
         }
     };
+    tokio::pin!(servers_done);
 
-    if let Err(e) = axum::serve(listener, app).await {
-        eprintln!("Server error: {}", e);
-        std::process::exit(1);
+    tokio::select! {
+        _ = &mut servers_done => return,
+        _ = stathost::shutdown_signal() => {}
+    }
+
+    // Stop accepting connections and uploads, then give in-flight requests
+    // up to `drain_timeout` to finish
+    manager.drain().start();
+    shutdown.cancel();
+    let uploads = manager.drain().in_flight();
+    println!(
+        "Shutting down, waiting up to {}s for {} in-flight upload(s)",
+        config.server.drain_timeout,
+        uploads.len()
+    );
+
+    let drain_timeout = Duration::from_secs(config.server.drain_timeout);
+    if tokio::time::timeout(drain_timeout, servers_done)
+        .await
+        .is_ok()
+    {
+        println!("Shutdown complete");
+        return;
+    }
+
+    let cut_off = manager.drain().in_flight();
+    if cut_off.is_empty() {
+        eprintln!("Drain timeout reached, closing remaining connections");
+    } else {
+        eprintln!(
+            "Drain timeout reached, cutting off {} upload(s): {}",
+            cut_off.len(),
+            cut_off.join(", ")
+        );
+    }
+    if let Err(e) = stathost::cleanup_temp_files(&buckets_dir).await {
+        eprintln!("Failed to clean up temp files: {}", e);
     }
 }
@@ -1,18 +1,25 @@
 use axum::{
     Json,
     extract::{Path, Query, Request, State},
-    http::StatusCode,
+    http::{StatusCode, header},
     response::{IntoResponse, Response},
 };
 use serde::Deserialize;
 use std::sync::Arc;
 
-use crate::{auth::extract_token, bucket::BucketManager};
+use crate::{
+    auth::{self, Token},
+    bucket::{Bucket, BucketManager, LoadedBucket},
+    config::Scope,
+    cors,
+};
 
 #[derive(Deserialize)]
 pub struct ListParams {
     #[serde(default)]
     detail: bool,
+    #[serde(default)]
+    precompressed: bool,
 }
 
 pub async fn list_files(
@@ -21,26 +28,36 @@ pub async fn list_files(
     Query(params): Query<ListParams>,
     request: Request,
 ) -> Response {
-    let Some(bucket) = manager.get_bucket(&bucket_name) else {
+    let loaded = request.extensions().get::<LoadedBucket>();
+    let Some(bucket) = manager.loaded_bucket(loaded, &bucket_name) else {
         return StatusCode::NOT_FOUND.into_response();
     };
 
-    let Some(token) = extract_token(request.headers()) else {
-        return StatusCode::UNAUTHORIZED.into_response();
+    let origin = request.headers().get(header::ORIGIN).cloned();
+    let mut response = match auth::authorize(&bucket, request.headers(), Scope::List, None).await {
+        Ok(token) => auth::granted(list(&bucket, &token, params).await),
+        Err(rejection) => rejection.into_response(),
     };
+    cors::apply(&bucket, origin.as_ref(), &mut response);
+    response
+}
 
-    if !bucket.validate_token(token) {
-        return StatusCode::FORBIDDEN.into_response();
-    }
-
+// Tokens limited to path prefixes only see the files under them.
+async fn list(bucket: &Bucket, token: &Token, params: ListParams) -> Response {
     if params.detail {
-        match bucket.list_files_detailed().await {
-            Ok(entries) => Json(entries).into_response(),
+        match bucket.list_files_detailed(params.precompressed).await {
+            Ok(mut entries) => {
+                entries.retain(|entry| auth::covers(token, &entry.path));
+                Json(entries).into_response()
+            }
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     } else {
-        match bucket.list_files().await {
-            Ok(files) => Json(files).into_response(),
+        match bucket.list_files(params.precompressed).await {
+            Ok(mut files) => {
+                files.retain(|file| auth::covers(token, file));
+                Json(files).into_response()
+            }
             Err(_) => StatusCode::INTERNAL_SERVER_ERROR.into_response(),
         }
     }
@@ -58,21 +75,55 @@ pub async fn openapi() -> Response {
             "/{bucket}/{path}": {
                 "get": {
                     "summary": "Serve a file",
+                    "security": [{}, {"bearerAuth": []}, {"basicAuth": []}],
                     "parameters": [
                         {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
                         {"name": "path", "in": "path", "required": true, "schema": {"type": "string"}},
                         {"name": "Range", "in": "header", "required": false, "schema": {"type": "string"},
-                         "description": "Single byte range (e.g. bytes=0-1023, bytes=1024-, bytes=-500). Multi-range requests are answered with the full body."}
+                         "description": "Byte range(s) (e.g. bytes=0-1023, bytes=1024-, bytes=-500, bytes=0-99,500-599). Overlapping ranges are merged; more than 16 ranges are answered with the full body."},
+                        {"name": "If-None-Match", "in": "header", "required": false, "schema": {"type": "string"},
+                         "description": "ETag list; a match returns 304."},
+                        {"name": "If-Modified-Since", "in": "header", "required": false, "schema": {"type": "string"},
+                         "description": "HTTP date; returns 304 if the file is unchanged. Ignored when If-None-Match is present."},
+                        {"name": "If-Range", "in": "header", "required": false, "schema": {"type": "string"},
+                         "description": "ETag or HTTP date; the Range header is only honoured if it matches the current file."}
+                    ],
+                    "responses": {
+                        "200": {"description": "File content (Accept-Ranges: bytes, ETag, Last-Modified). A precompressed .br/.gz sibling is served with Content-Encoding when Accept-Encoding allows it. For directories in buckets with autoindex enabled, an HTML listing (or JSON array of FileEntry with Accept: application/json)."},
+                        "206": {"description": "Partial file content with Content-Range: bytes start-end/total, or a multipart/byteranges body when several ranges remain after merging"},
+                        "304": {"description": "Not modified; carries ETag and Last-Modified"},
+                        "301": {"description": "Redirect rule matched; see Location"},
+                        "302": {"description": "Redirect rule matched; see Location"},
+                        "307": {"description": "Redirect rule matched; see Location"},
+                        "308": {"description": "Redirect rule matched, or directory requested without a trailing slash in a bucket with autoindex enabled"},
+                        "401": {"description": "Private bucket and no or unknown credentials; carries WWW-Authenticate"},
+                        "403": {"description": "Private bucket and the token lacks the read scope or path"},
+                        "404": {"description": "File or bucket not found"},
+                        "416": {"description": "Range not satisfiable; Content-Range: bytes */total"},
+                        "429": {"description": "Rate limited, or locked out after failed authentication"}
+                    }
+                },
+                "head": {
+                    "summary": "File metadata without a body",
+                    "security": [{}, {"bearerAuth": []}, {"basicAuth": []}],
+                    "parameters": [
+                        {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
+                        {"name": "path", "in": "path", "required": true, "schema": {"type": "string"}},
+                        {"name": "If-None-Match", "in": "header", "required": false, "schema": {"type": "string"}},
+                        {"name": "If-Modified-Since", "in": "header", "required": false, "schema": {"type": "string"}}
                     ],
                     "responses": {
-                        "200": {"description": "File content (Accept-Ranges: bytes)"},
-                        "206": {"description": "Partial file content with Content-Range: bytes start-end/total"},
+                        "200": {"description": "Content-Length, Content-Type, ETag, Last-Modified and Accept-Ranges of the file"},
+                        "304": {"description": "Not modified"},
+                        "401": {"description": "Private bucket and no or unknown credentials; carries WWW-Authenticate"},
+                        "403": {"description": "Private bucket and the token lacks the read scope or path"},
                         "404": {"description": "File or bucket not found"},
-                        "416": {"description": "Range not satisfiable; Content-Range: bytes */total"}
+                        "429": {"description": "Rate limited, or locked out after failed authentication"}
                     }
                 },
                 "put": {
                     "summary": "Upload or update a file",
+                    "description": "Buckets with `[auth.client_certs]` also accept a TLS client certificate instead of a token",
                     "security": [{"bearerAuth": []}],
                     "parameters": [
                         {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
@@ -84,11 +135,13 @@ pub async fn openapi() -> Response {
                     "responses": {
                         "201": {"description": "File created/updated"},
                         "401": {"description": "Unauthorized"},
-                        "403": {"description": "Forbidden"}
+                        "403": {"description": "Token unknown, or lacking the scope or path"},
+                        "429": {"description": "Rate limited, or locked out after failed authentication"}
                     }
                 },
                 "delete": {
                     "summary": "Delete a file",
+                    "description": "Buckets with `[auth.client_certs]` also accept a TLS client certificate instead of a token",
                     "security": [{"bearerAuth": []}],
                     "parameters": [
                         {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
@@ -97,8 +150,24 @@ pub async fn openapi() -> Response {
                     "responses": {
                         "204": {"description": "File deleted"},
                         "401": {"description": "Unauthorized"},
-                        "403": {"description": "Forbidden"},
-                        "404": {"description": "File not found"}
+                        "403": {"description": "Token unknown, or lacking the scope or path"},
+                        "404": {"description": "File not found"},
+                        "429": {"description": "Rate limited, or locked out after failed authentication"}
+                    }
+                },
+                "options": {
+                    "summary": "CORS preflight",
+                    "parameters": [
+                        {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
+                        {"name": "path", "in": "path", "required": true, "schema": {"type": "string"}},
+                        {"name": "Origin", "in": "header", "required": false, "schema": {"type": "string"}},
+                        {"name": "Access-Control-Request-Method", "in": "header", "required": false, "schema": {"type": "string"}},
+                        {"name": "Access-Control-Request-Headers", "in": "header", "required": false, "schema": {"type": "string"}}
+                    ],
+                    "responses": {
+                        "204": {"description": "Preflight allowed by the bucket's [cors] section (Access-Control-Allow-* headers), or plain OPTIONS with an Allow header"},
+                        "403": {"description": "Origin, method or headers not allowed"},
+                        "404": {"description": "Bucket not found"}
                     }
                 }
             },
@@ -109,7 +178,9 @@ pub async fn openapi() -> Response {
                     "parameters": [
                         {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
                         {"name": "detail", "in": "query", "required": false, "schema": {"type": "boolean", "default": false},
-                         "description": "When true, returns objects with path, size (bytes) and mtime (Unix seconds) instead of plain path strings."}
+                         "description": "When true, returns objects with path, size (bytes) and mtime (Unix seconds) instead of plain path strings."},
+                        {"name": "precompressed", "in": "query", "required": false, "schema": {"type": "boolean", "default": false},
+                         "description": "When true, includes .br/.gz sidecar files that sit next to another file in the bucket."}
                     ],
                     "responses": {
                         "200": {
@@ -120,7 +191,76 @@ pub async fn openapi() -> Response {
                             ]}}}
                         },
                         "401": {"description": "Unauthorized"},
-                        "403": {"description": "Forbidden"}
+                        "403": {"description": "Token unknown, or lacking the scope or path"},
+                        "429": {"description": "Rate limited, or locked out after failed authentication"}
+                    }
+                },
+                "options": {
+                    "summary": "CORS preflight",
+                    "parameters": [
+                        {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
+                        {"name": "Origin", "in": "header", "required": false, "schema": {"type": "string"}},
+                        {"name": "Access-Control-Request-Method", "in": "header", "required": false, "schema": {"type": "string"}},
+                        {"name": "Access-Control-Request-Headers", "in": "header", "required": false, "schema": {"type": "string"}}
+                    ],
+                    "responses": {
+                        "204": {"description": "Preflight allowed, or plain OPTIONS with an Allow header"},
+                        "403": {"description": "Origin, method or headers not allowed"},
+                        "404": {"description": "Bucket not found"}
+                    }
+                }
+            },
+            "/{bucket}/_meta/presign": {
+                "post": {
+                    "summary": "Create a presigned URL for one file",
+                    "description": "The URL carries expires and signature query parameters and can be used for GET/HEAD (method GET) or PUT (method PUT) without a token until it expires. Requires the read or write scope for the path.",
+                    "security": [{"bearerAuth": []}],
+                    "parameters": [
+                        {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}}
+                    ],
+                    "requestBody": {
+                        "required": true,
+                        "content": {"application/json": {"schema": {
+                            "type": "object",
+                            "required": ["path", "method"],
+                            "properties": {
+                                "path": {"type": "string"},
+                                "method": {"type": "string", "enum": ["GET", "PUT"]},
+                                "expires_in": {"type": "integer", "default": 3600, "description": "Seconds, up to the bucket's max_expires"}
+                            }
+                        }}}
+                    },
+                    "responses": {
+                        "200": {
+                            "description": "Presigned URL, relative to the server",
+                            "content": {"application/json": {"schema": {
+                                "type": "object",
+                                "properties": {
+                                    "url": {"type": "string"},
+                                    "method": {"type": "string"},
+                                    "expires": {"type": "integer", "format": "int64", "description": "Unix seconds (UTC)"}
+                                }
+                            }}}
+                        },
+                        "400": {"description": "Invalid path or expires_in"},
+                        "401": {"description": "Unauthorized"},
+                        "403": {"description": "Token unknown, or lacking the scope or path"},
+                        "404": {"description": "Bucket not found, or presigned URLs not enabled for it"},
+                        "429": {"description": "Rate limited, or locked out after failed authentication"}
+                    }
+                },
+                "options": {
+                    "summary": "CORS preflight",
+                    "parameters": [
+                        {"name": "bucket", "in": "path", "required": true, "schema": {"type": "string"}},
+                        {"name": "Origin", "in": "header", "required": false, "schema": {"type": "string"}},
+                        {"name": "Access-Control-Request-Method", "in": "header", "required": false, "schema": {"type": "string"}},
+                        {"name": "Access-Control-Request-Headers", "in": "header", "required": false, "schema": {"type": "string"}}
+                    ],
+                    "responses": {
+                        "204": {"description": "Preflight allowed, or plain OPTIONS with an Allow header"},
+                        "403": {"description": "Origin, method or headers not allowed"},
+                        "404": {"description": "Bucket not found"}
                     }
                 }
             }
@@ -140,7 +280,13 @@ pub async fn openapi() -> Response {
             "securitySchemes": {
                 "bearerAuth": {
                     "type": "http",
-                    "scheme": "bearer"
+                    "scheme": "bearer",
+                    "description": "A bucket token, or a JWT with `stathost:<bucket>:<scope>` grants when the server has JWT auth configured"
+                },
+                "basicAuth": {
+                    "type": "http",
+                    "scheme": "basic",
+                    "description": "Any user name, with a token as the password"
                 }
             }
         }
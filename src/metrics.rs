@@ -0,0 +1,353 @@
+use axum::{
+    body::{Body, Bytes},
+    extract::{Request, State},
+    http::{HeaderMap, Method, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use http_body::{Frame, SizeHint};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time::Instant;
+
+use crate::{
+    auth::{Rejection, extract_token, secrets_equal},
+    bucket::BucketManager,
+    vhost,
+};
+
+// Upper bounds of the request duration histogram, in seconds
+const DURATION_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+#[derive(Default)]
+struct RequestStats {
+    count: u64,
+    duration_sum: f64,
+    // Cumulative, like the exposition format
+    buckets: [u64; DURATION_BUCKETS.len()],
+}
+
+#[derive(Default)]
+struct ByteCounters {
+    served: Arc<AtomicU64>,
+    uploaded: Arc<AtomicU64>,
+}
+
+// Keyed by (bucket, method, status); BTreeMaps keep the output stable.
+#[derive(Default)]
+pub struct Metrics {
+    token: Option<String>,
+    requests: Mutex<BTreeMap<(String, &'static str, u16), RequestStats>>,
+    bytes: Mutex<BTreeMap<String, ByteCounters>>,
+    temp_files_cleaned: AtomicU64,
+}
+
+impl Metrics {
+    pub fn with_token(token: String) -> Self {
+        Self {
+            token: Some(token),
+            ..Self::default()
+        }
+    }
+
+    pub fn add_temp_files_cleaned(&self, count: usize) {
+        self.temp_files_cleaned
+            .fetch_add(count as u64, Ordering::Relaxed);
+    }
+
+    fn record_request(&self, bucket: &str, method: &'static str, status: u16, seconds: f64) {
+        let Ok(mut requests) = self.requests.lock() else {
+            return;
+        };
+        let stats = requests
+            .entry((bucket.to_string(), method, status))
+            .or_default();
+        stats.count += 1;
+        stats.duration_sum += seconds;
+        for (count, le) in stats.buckets.iter_mut().zip(DURATION_BUCKETS) {
+            if seconds <= le {
+                *count += 1;
+            }
+        }
+    }
+
+    fn byte_counters(&self, bucket: &str) -> (Arc<AtomicU64>, Arc<AtomicU64>) {
+        let Ok(mut bytes) = self.bytes.lock() else {
+            return Default::default();
+        };
+        let counters = bytes.entry(bucket.to_string()).or_default();
+        (counters.served.clone(), counters.uploaded.clone())
+    }
+}
+
+// Counts data bytes as they pass through, keeping the inner body's size
+// hint so Content-Length handling is unchanged.
+struct CountingBody {
+    inner: Body,
+    counter: Arc<AtomicU64>,
+}
+
+impl http_body::Body for CountingBody {
+    type Data = Bytes;
+    type Error = axum::Error;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, axum::Error>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        if let Poll::Ready(Some(Ok(frame))) = &poll
+            && let Some(data) = frame.data_ref()
+        {
+            self.counter.fetch_add(data.len() as u64, Ordering::Relaxed);
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+fn counting(body: Body, counter: Arc<AtomicU64>) -> Body {
+    Body::new(CountingBody {
+        inner: body,
+        counter,
+    })
+}
+
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::HEAD => "HEAD",
+        Method::PUT => "PUT",
+        Method::DELETE => "DELETE",
+        Method::OPTIONS => "OPTIONS",
+        Method::POST => "POST",
+        _ => "OTHER",
+    }
+}
+
+// Records request counts and latency (time until the response headers are
+// ready), plus request and response body bytes per bucket.
+pub async fn track_metrics(
+    State(manager): State<Arc<BucketManager>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let start = Instant::now();
+    // Requests outside any bucket (unknown names, `/openapi.json`, the
+    // metrics endpoint itself) share the empty label, so scanners probing
+    // random paths cannot blow up the number of series.
+    let bucket = vhost::request_bucket(&manager, &request).unwrap_or_default();
+    let method = method_label(request.method());
+    let (served, uploaded) = manager.metrics().byte_counters(&bucket);
+
+    let request = request.map(|body| counting(body, uploaded));
+    let response = next.run(request).await;
+
+    manager.metrics().record_request(
+        &bucket,
+        method,
+        response.status().as_u16(),
+        start.elapsed().as_secs_f64(),
+    );
+    response.map(|body| counting(body, served))
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn write_header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn render_requests(metrics: &Metrics, out: &mut String) {
+    let Ok(requests) = metrics.requests.lock() else {
+        return;
+    };
+
+    write_header(
+        out,
+        "stathost_http_requests_total",
+        "counter",
+        "HTTP requests handled.",
+    );
+    for ((bucket, method, status), stats) in requests.iter() {
+        let _ = writeln!(
+            out,
+            "stathost_http_requests_total{{bucket=\"{}\",method=\"{}\",status=\"{}\"}} {}",
+            escape_label(bucket),
+            method,
+            status,
+            stats.count
+        );
+    }
+
+    write_header(
+        out,
+        "stathost_http_request_duration_seconds",
+        "histogram",
+        "Time until the response headers were ready.",
+    );
+    for ((bucket, method, status), stats) in requests.iter() {
+        let labels = format!(
+            "bucket=\"{}\",method=\"{}\",status=\"{}\"",
+            escape_label(bucket),
+            method,
+            status
+        );
+        for (count, le) in stats.buckets.iter().zip(DURATION_BUCKETS) {
+            let _ = writeln!(
+                out,
+                "stathost_http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                labels, le, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "stathost_http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+            labels, stats.count
+        );
+        let _ = writeln!(
+            out,
+            "stathost_http_request_duration_seconds_sum{{{}}} {}",
+            labels, stats.duration_sum
+        );
+        let _ = writeln!(
+            out,
+            "stathost_http_request_duration_seconds_count{{{}}} {}",
+            labels, stats.count
+        );
+    }
+}
+
+fn render_bytes(metrics: &Metrics, out: &mut String) {
+    let Ok(bytes) = metrics.bytes.lock() else {
+        return;
+    };
+
+    write_header(
+        out,
+        "stathost_bytes_served_total",
+        "counter",
+        "Response body bytes sent.",
+    );
+    for (bucket, counters) in bytes.iter() {
+        let _ = writeln!(
+            out,
+            "stathost_bytes_served_total{{bucket=\"{}\"}} {}",
+            escape_label(bucket),
+            counters.served.load(Ordering::Relaxed)
+        );
+    }
+
+    write_header(
+        out,
+        "stathost_bytes_uploaded_total",
+        "counter",
+        "Request body bytes received.",
+    );
+    for (bucket, counters) in bytes.iter() {
+        let _ = writeln!(
+            out,
+            "stathost_bytes_uploaded_total{{bucket=\"{}\"}} {}",
+            escape_label(bucket),
+            counters.uploaded.load(Ordering::Relaxed)
+        );
+    }
+}
+
+async fn render(manager: &BucketManager) -> String {
+    let metrics = manager.metrics();
+    let mut out = String::new();
+    render_requests(metrics, &mut out);
+    render_bytes(metrics, &mut out);
+
+    write_header(
+        &mut out,
+        "stathost_uploads_in_flight",
+        "gauge",
+        "Uploads currently being received.",
+    );
+    let _ = writeln!(
+        out,
+        "stathost_uploads_in_flight {}",
+        manager.drain().in_flight().len()
+    );
+
+    write_header(
+        &mut out,
+        "stathost_temp_files_cleaned_total",
+        "counter",
+        "Stale upload temp files removed at startup.",
+    );
+    let _ = writeln!(
+        out,
+        "stathost_temp_files_cleaned_total {}",
+        metrics.temp_files_cleaned.load(Ordering::Relaxed)
+    );
+
+    write_header(
+        &mut out,
+        "stathost_bucket_disk_usage_bytes",
+        "gauge",
+        "Total size of the files in a bucket.",
+    );
+    for name in manager.bucket_names() {
+        let Some(bucket) = manager.get_bucket(&name) else {
+            continue;
+        };
+        let Ok(entries) = bucket.list_files_detailed(true).await else {
+            continue;
+        };
+        let size: u64 = entries.iter().map(|entry| entry.size).sum();
+        let _ = writeln!(
+            out,
+            "stathost_bucket_disk_usage_bytes{{bucket=\"{}\"}} {}",
+            escape_label(&name),
+            size
+        );
+    }
+
+    out
+}
+
+pub async fn serve_metrics(
+    State(manager): State<Arc<BucketManager>>,
+    headers: HeaderMap,
+) -> Response {
+    if let Some(expected) = manager.metrics().token.as_deref() {
+        // A wrong token counts towards rate limit lockouts like bucket tokens
+        let Some(token) = extract_token(&headers) else {
+            return Rejection::Missing.into_response();
+        };
+        if !secrets_equal(&token, expected) {
+            return Rejection::Invalid.into_response();
+        }
+    }
+
+    (
+        [(
+            header::CONTENT_TYPE,
+            "text/plain; version=0.0.4; charset=utf-8",
+        )],
+        render(&manager).await,
+    )
+        .into_response()
+}
@@ -0,0 +1,218 @@
+use axum::{
+    Json,
+    extract::{Extension, Path, State},
+    http::{HeaderMap, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{
+    auth::{self, Rejection},
+    autoindex::encode_segment,
+    bucket::{Bucket, BucketManager, LoadedBucket},
+    config::{PresignConfig, Scope},
+    cors,
+};
+
+const DEFAULT_EXPIRES_IN: u64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum PresignMethod {
+    Get,
+    Put,
+}
+
+impl PresignMethod {
+    fn as_str(self) -> &'static str {
+        match self {
+            PresignMethod::Get => "GET",
+            PresignMethod::Put => "PUT",
+        }
+    }
+
+    fn scope(self) -> Scope {
+        match self {
+            PresignMethod::Get => Scope::Read,
+            PresignMethod::Put => Scope::Write,
+        }
+    }
+}
+
+pub fn validate(config: &PresignConfig) -> Result<(), String> {
+    if config.secret.len() < 16 {
+        return Err("presign secret must be at least 16 characters".to_string());
+    }
+    Ok(())
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0)
+}
+
+// The bucket name is signed too, so buckets sharing a secret cannot replay
+// each other's URLs.
+fn mac(
+    secret: &str,
+    method: PresignMethod,
+    bucket: &str,
+    path: &str,
+    expires: u64,
+) -> Option<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).ok()?;
+    mac.update(
+        format!(
+            "{}\n{}\n{}\n{}",
+            method.as_str(),
+            bucket,
+            path.trim_start_matches('/'),
+            expires
+        )
+        .as_bytes(),
+    );
+    Some(mac)
+}
+
+// Checks the `expires` and `signature` query parameters of a presigned URL.
+// Returns `None` when the request carries no signature, so the caller falls
+// back to token auth.
+pub fn check(
+    bucket: &Bucket,
+    bucket_name: &str,
+    method: PresignMethod,
+    path: &str,
+    query: Option<&str>,
+) -> Option<Result<(), Rejection>> {
+    let mut expires = None;
+    let mut signature = None;
+    for (key, value) in query?.split('&').filter_map(|pair| pair.split_once('=')) {
+        match key {
+            "expires" => expires = Some(value),
+            "signature" => signature = Some(value),
+            _ => {}
+        }
+    }
+    let signature = signature?;
+
+    if valid_signature(bucket, bucket_name, method, path, expires, signature) {
+        Some(Ok(()))
+    } else {
+        Some(Err(Rejection::Invalid))
+    }
+}
+
+fn valid_signature(
+    bucket: &Bucket,
+    bucket_name: &str,
+    method: PresignMethod,
+    path: &str,
+    expires: Option<&str>,
+    signature: &str,
+) -> bool {
+    let Some(config) = &bucket.config().presign else {
+        return false;
+    };
+    let Some(expires) = expires.and_then(|e| e.parse::<u64>().ok()) else {
+        return false;
+    };
+    if expires < now() {
+        return false;
+    }
+    let (Some(signature), Some(mac)) = (
+        auth::hex_decode(signature),
+        mac(&config.secret, method, bucket_name, path, expires),
+    ) else {
+        return false;
+    };
+    // Constant-time comparison
+    mac.verify_slice(&signature).is_ok()
+}
+
+#[derive(Deserialize)]
+pub struct PresignRequest {
+    path: String,
+    method: PresignMethod,
+    // Seconds from now; defaults to an hour
+    expires_in: Option<u64>,
+}
+
+pub async fn presign(
+    State(manager): State<Arc<BucketManager>>,
+    Path(bucket_name): Path<String>,
+    loaded: Option<Extension<LoadedBucket>>,
+    headers: HeaderMap,
+    Json(params): Json<PresignRequest>,
+) -> Response {
+    let Some(bucket) = manager.loaded_bucket(loaded.as_deref(), &bucket_name) else {
+        return StatusCode::NOT_FOUND.into_response();
+    };
+
+    let mut response = sign(&bucket, &bucket_name, &headers, params).await;
+    cors::apply(&bucket, headers.get(header::ORIGIN), &mut response);
+    response
+}
+
+async fn sign(
+    bucket: &Bucket,
+    bucket_name: &str,
+    headers: &HeaderMap,
+    params: PresignRequest,
+) -> Response {
+    let Some(config) = &bucket.config().presign else {
+        return (
+            StatusCode::NOT_FOUND,
+            "Presigned URLs are not enabled for this bucket",
+        )
+            .into_response();
+    };
+
+    // A token can only hand out the access it has itself
+    let path = params.path.trim_start_matches('/');
+    if let Err(rejection) =
+        auth::authorize(bucket, headers, params.method.scope(), Some(path)).await
+    {
+        return rejection.into_response();
+    }
+    if path.is_empty() || bucket.resolve_path(path).is_none() {
+        return (StatusCode::BAD_REQUEST, "Invalid path").into_response();
+    }
+
+    let expires_in = params.expires_in.unwrap_or(DEFAULT_EXPIRES_IN);
+    if expires_in == 0 || expires_in > config.max_expires {
+        return (
+            StatusCode::BAD_REQUEST,
+            format!("expires_in must be between 1 and {}", config.max_expires),
+        )
+            .into_response();
+    }
+    let expires = now() + expires_in;
+
+    let Some(mac) = mac(&config.secret, params.method, bucket_name, path, expires) else {
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let signature = mac.finalize().into_bytes();
+    let encoded_path: Vec<String> = path.split('/').map(encode_segment).collect();
+    let url = format!(
+        "/{}/{}?expires={}&signature={}",
+        encode_segment(bucket_name),
+        encoded_path.join("/"),
+        expires,
+        auth::hex_encode(&signature)
+    );
+
+    auth::granted(
+        Json(serde_json::json!({
+            "url": url,
+            "method": params.method.as_str(),
+            "expires": expires,
+        }))
+        .into_response(),
+    )
+}
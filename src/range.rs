@@ -0,0 +1,148 @@
+use axum::body::{Body, Bytes};
+use futures_util::{StreamExt, stream};
+use std::sync::Arc;
+use tokio::fs::File;
+use tokio::io::{AsyncReadExt, AsyncSeekExt, SeekFrom};
+use tokio_util::io::ReaderStream;
+
+// Requests with more ranges than this are answered with the full body, so
+// a client can't make us emit thousands of tiny parts.
+pub const MAX_RANGES: usize = 16;
+
+enum RangeSpec {
+    FromTo(u64, u64),
+    From(u64),
+    Suffix(u64),
+}
+
+pub struct Ranges(Vec<RangeSpec>);
+
+pub fn parse_ranges(value: &str) -> Option<Ranges> {
+    let specs = value.strip_prefix("bytes=")?;
+    let specs: Vec<RangeSpec> = specs
+        .split(',')
+        .map(str::trim)
+        .filter(|s| !s.is_empty())
+        .map(parse_spec)
+        .collect::<Option<_>>()?;
+
+    if specs.is_empty() || specs.len() > MAX_RANGES {
+        return None;
+    }
+    Some(Ranges(specs))
+}
+
+fn parse_spec(spec: &str) -> Option<RangeSpec> {
+    let (start, end) = spec.split_once('-')?;
+    match (start.is_empty(), end.is_empty()) {
+        (false, false) => {
+            let start: u64 = start.parse().ok()?;
+            let end: u64 = end.parse().ok()?;
+            if start > end {
+                return None;
+            }
+            Some(RangeSpec::FromTo(start, end))
+        }
+        (false, true) => Some(RangeSpec::From(start.parse().ok()?)),
+        (true, false) => Some(RangeSpec::Suffix(end.parse().ok()?)),
+        (true, true) => None,
+    }
+}
+
+fn resolve_spec(spec: &RangeSpec, total: u64) -> Option<(u64, u64)> {
+    match *spec {
+        RangeSpec::FromTo(start, end) => (start < total).then(|| (start, end.min(total - 1))),
+        RangeSpec::From(start) => (start < total).then(|| (start, total - 1)),
+        RangeSpec::Suffix(n) => (n > 0 && total > 0).then(|| (total.saturating_sub(n), total - 1)),
+    }
+}
+
+// Resolves the requested ranges against the file size, dropping
+// unsatisfiable ones and merging overlapping or adjacent ranges.
+// An empty result means the request is unsatisfiable.
+pub fn resolve_ranges(ranges: &Ranges, total: u64) -> Vec<(u64, u64)> {
+    let mut resolved: Vec<(u64, u64)> = ranges
+        .0
+        .iter()
+        .filter_map(|spec| resolve_spec(spec, total))
+        .collect();
+    resolved.sort_unstable();
+
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(resolved.len());
+    for (start, end) in resolved {
+        match merged.last_mut() {
+            Some(last) if start <= last.1.saturating_add(1) => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+fn part_header(boundary: &str, mime: &str, start: u64, end: u64, total: u64) -> String {
+    format!(
+        "\r\n--{}\r\nContent-Type: {}\r\nContent-Range: bytes {}-{}/{}\r\n\r\n",
+        boundary, mime, start, end, total
+    )
+}
+
+fn closing_delimiter(boundary: &str) -> String {
+    format!("\r\n--{}--\r\n", boundary)
+}
+
+pub fn multipart_len(ranges: &[(u64, u64)], boundary: &str, mime: &str, total: u64) -> u64 {
+    let parts: u64 = ranges
+        .iter()
+        .map(|&(start, end)| {
+            part_header(boundary, mime, start, end, total).len() as u64 + (end - start + 1)
+        })
+        .sum();
+    parts + closing_delimiter(boundary).len() as u64
+}
+
+// Each part reads through a dup of the same file descriptor, so all parts
+// come from the version of the file that was opened, even if it is
+// replaced mid-response. Parts are streamed strictly one after another,
+// which keeps the shared cursor consistent.
+async fn part_reader(
+    file: &std::fs::File,
+    start: u64,
+    len: u64,
+) -> std::io::Result<tokio::io::Take<File>> {
+    let mut file = File::from_std(file.try_clone()?);
+    file.seek(SeekFrom::Start(start)).await?;
+    Ok(file.take(len))
+}
+
+pub fn multipart_body(
+    file: std::fs::File,
+    ranges: Vec<(u64, u64)>,
+    boundary: &str,
+    mime: &str,
+    total: u64,
+) -> Body {
+    let file = Arc::new(file);
+    let parts: Vec<_> = ranges
+        .into_iter()
+        .map(|(start, end)| (part_header(boundary, mime, start, end, total), start, end))
+        .collect();
+    let closing = closing_delimiter(boundary);
+
+    let stream = stream::iter(parts)
+        .then(move |(head, start, end)| {
+            let file = file.clone();
+            async move {
+                let head = stream::once(async move { Ok(Bytes::from(head)) });
+                let data = match part_reader(&file, start, end - start + 1).await {
+                    Ok(reader) => ReaderStream::new(reader).left_stream(),
+                    Err(e) => stream::once(async move { Err(e) }).right_stream(),
+                };
+                head.chain(data)
+            }
+        })
+        .flatten()
+        .chain(stream::once(async move {
+            Ok::<_, std::io::Error>(Bytes::from(closing))
+        }));
+
+    Body::from_stream(stream)
+}
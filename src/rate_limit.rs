@@ -0,0 +1,303 @@
+use axum::{
+    extract::{Request, State},
+    http::{StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::{
+    auth::AuthOutcome,
+    bucket::BucketManager,
+    config::{RateConfig, RateLimitConfig},
+    ip_filter, vhost,
+};
+
+const SUMMARY_INTERVAL: Duration = Duration::from_secs(60);
+
+fn validate(rate: Option<RateConfig>) -> Result<Option<RateConfig>, String> {
+    match rate {
+        Some(rate) if !(rate.rate.is_finite() && rate.rate > 0.0) || rate.burst == 0 => {
+            Err("rate limits need a positive rate and burst".to_string())
+        }
+        rate => Ok(rate),
+    }
+}
+
+struct TokenBucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    fn full(rate: RateConfig, now: Instant) -> Self {
+        Self {
+            tokens: f64::from(rate.burst),
+            updated: now,
+        }
+    }
+
+    fn refill(&mut self, rate: RateConfig, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate.rate).min(f64::from(rate.burst));
+        self.updated = now;
+    }
+
+    // Takes a token, or says how long until the next one
+    fn take(&mut self, rate: RateConfig, now: Instant) -> Result<(), Duration> {
+        self.refill(rate, now);
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate.rate))
+        }
+    }
+}
+
+#[derive(Default)]
+struct Entry {
+    requests: Option<TokenBucket>,
+    failures: Option<TokenBucket>,
+    locked_until: Option<Instant>,
+}
+
+// Request and failed-authentication limits for one kind of key, client
+// addresses or bucket names.
+struct Table<K> {
+    requests: Option<RateConfig>,
+    failures: Option<RateConfig>,
+    entries: Mutex<HashMap<K, Entry>>,
+}
+
+impl<K: Eq + Hash + Clone> Table<K> {
+    fn new(requests: Option<RateConfig>, failures: Option<RateConfig>) -> Self {
+        Self {
+            requests,
+            failures,
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    // A lockout only turns away requests that carry credentials, so public
+    // reads keep working while token guessing is paused.
+    fn admit(&self, key: &K, credentials: bool, now: Instant) -> Result<(), Duration> {
+        if self.requests.is_none() && self.failures.is_none() {
+            return Ok(());
+        }
+        let Ok(mut entries) = self.entries.lock() else {
+            return Ok(());
+        };
+        let entry = entries.entry(key.clone()).or_default();
+        if credentials
+            && let Some(until) = entry.locked_until
+            && until > now
+        {
+            return Err(until - now);
+        }
+        match self.requests {
+            Some(rate) => entry
+                .requests
+                .get_or_insert_with(|| TokenBucket::full(rate, now))
+                .take(rate, now),
+            None => Ok(()),
+        }
+    }
+
+    // Whether this failure used up the allowance and started a lockout
+    fn fail(&self, key: &K, lockout: Duration, now: Instant) -> bool {
+        let Some(rate) = self.failures else {
+            return false;
+        };
+        let Ok(mut entries) = self.entries.lock() else {
+            return false;
+        };
+        let entry = entries.entry(key.clone()).or_default();
+        if entry.locked_until.is_some_and(|until| until > now) {
+            return false;
+        }
+        let failures = entry
+            .failures
+            .get_or_insert_with(|| TokenBucket::full(rate, now));
+        if failures.take(rate, now).is_ok() {
+            return false;
+        }
+        // The allowance starts over once the lockout ends
+        entry.failures = None;
+        entry.locked_until = Some(now + lockout);
+        true
+    }
+
+    // Forgets keys that are back to a full allowance and not locked out
+    fn prune(&self, now: Instant) {
+        let Ok(mut entries) = self.entries.lock() else {
+            return;
+        };
+        entries.retain(|_, entry| {
+            let full = |bucket: &mut Option<TokenBucket>, rate: Option<RateConfig>| match (
+                bucket.as_mut(),
+                rate,
+            ) {
+                (Some(bucket), Some(rate)) => {
+                    bucket.refill(rate, now);
+                    bucket.tokens >= f64::from(rate.burst)
+                }
+                _ => true,
+            };
+            entry.locked_until.is_some_and(|until| until > now)
+                || !full(&mut entry.requests, self.requests)
+                || !full(&mut entry.failures, self.failures)
+        });
+    }
+}
+
+pub struct RateLimiter {
+    ips: Table<IpAddr>,
+    buckets: Table<String>,
+    lockout: Duration,
+    // Reset by each summary log line
+    limited: AtomicU64,
+    auth_failures: AtomicU64,
+    lockouts: AtomicU64,
+}
+
+impl RateLimiter {
+    // `None` when `[server.rate_limit]` sets no limits
+    pub fn new(config: &RateLimitConfig) -> Result<Option<Self>, String> {
+        let failures = config.auth_failures.as_ref();
+        if config.ip.is_none() && config.bucket.is_none() && failures.is_none() {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            ips: Table::new(validate(config.ip)?, validate(failures.and_then(|f| f.ip))?),
+            buckets: Table::new(
+                validate(config.bucket)?,
+                validate(failures.and_then(|f| f.bucket))?,
+            ),
+            lockout: Duration::from_secs(failures.map_or(0, |f| f.lockout)),
+            limited: AtomicU64::new(0),
+            auth_failures: AtomicU64::new(0),
+            lockouts: AtomicU64::new(0),
+        }))
+    }
+
+    fn admit(
+        &self,
+        ip: Option<IpAddr>,
+        bucket: Option<&str>,
+        credentials: bool,
+    ) -> Result<(), Duration> {
+        let now = Instant::now();
+        let result = ip
+            .map_or(Ok(()), |ip| self.ips.admit(&ip, credentials, now))
+            .and_then(|()| {
+                bucket.map_or(Ok(()), |bucket| {
+                    self.buckets.admit(&bucket.to_string(), credentials, now)
+                })
+            });
+        if result.is_err() {
+            self.limited.fetch_add(1, Ordering::Relaxed);
+        }
+        result
+    }
+
+    fn record_failure(&self, ip: Option<IpAddr>, bucket: Option<&str>) {
+        let now = Instant::now();
+        self.auth_failures.fetch_add(1, Ordering::Relaxed);
+        if let Some(ip) = ip
+            && self.ips.fail(&ip, self.lockout, now)
+        {
+            self.lockouts.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "Locking out {} for {}s after repeated failed authentication",
+                ip,
+                self.lockout.as_secs()
+            );
+        }
+        if let Some(bucket) = bucket
+            && self.buckets.fail(&bucket.to_string(), self.lockout, now)
+        {
+            self.lockouts.fetch_add(1, Ordering::Relaxed);
+            eprintln!(
+                "Locking bucket {} for {}s after repeated failed authentication",
+                bucket,
+                self.lockout.as_secs()
+            );
+        }
+    }
+}
+
+// Tokens, Basic credentials and presigned URL signatures
+fn has_credentials(request: &Request) -> bool {
+    request.headers().contains_key(header::AUTHORIZATION)
+        || request
+            .uri()
+            .query()
+            .is_some_and(|query| query.split('&').any(|pair| pair.starts_with("signature=")))
+}
+
+fn too_many_requests(retry_after: Duration) -> Response {
+    let seconds = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+    (
+        StatusCode::TOO_MANY_REQUESTS,
+        [(header::RETRY_AFTER, seconds.to_string())],
+        "Too many requests",
+    )
+        .into_response()
+}
+
+pub async fn rate_limit(
+    State(manager): State<Arc<BucketManager>>,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(limiter) = manager.rate_limiter() else {
+        return next.run(request).await;
+    };
+    let ip = ip_filter::client_ip(&manager, &request);
+    let bucket = vhost::load_request_bucket(&manager, &mut request).map(|loaded| loaded.name);
+    if let Err(retry_after) = limiter.admit(ip, bucket.as_deref(), has_credentials(&request)) {
+        return too_many_requests(retry_after);
+    }
+
+    let response = next.run(request).await;
+    // Missing credentials and tokens lacking a scope are not guesses
+    if response.extensions().get::<AuthOutcome>() == Some(&AuthOutcome::Invalid) {
+        limiter.record_failure(ip, bucket.as_deref());
+    }
+    response
+}
+
+// Logs what the limits did every minute, when they did anything, and drops
+// state for clients that went quiet.
+pub async fn log_rate_limits(manager: Arc<BucketManager>) {
+    let Some(limiter) = manager.rate_limiter() else {
+        return;
+    };
+    let mut interval = tokio::time::interval(SUMMARY_INTERVAL);
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        let now = Instant::now();
+        limiter.ips.prune(now);
+        limiter.buckets.prune(now);
+
+        let limited = limiter.limited.swap(0, Ordering::Relaxed);
+        let auth_failures = limiter.auth_failures.swap(0, Ordering::Relaxed);
+        let lockouts = limiter.lockouts.swap(0, Ordering::Relaxed);
+        if limited + auth_failures + lockouts > 0 {
+            println!(
+                "Rate limits in the last {}s: {} request(s) refused, {} failed authentication(s), {} lockout(s)",
+                SUMMARY_INTERVAL.as_secs(),
+                limited,
+                auth_failures,
+                lockouts
+            );
+        }
+    }
+}
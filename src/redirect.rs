@@ -0,0 +1,171 @@
+use axum::http::StatusCode;
+use std::collections::HashMap;
+
+use crate::{autoindex, config::RedirectRule};
+
+pub enum Action {
+    // Target is either an absolute URL or a bucket path starting with `/`
+    Redirect(StatusCode, String),
+    // Bucket path to serve in place of the requested one
+    Rewrite(String),
+}
+
+fn is_external(to: &str) -> bool {
+    to.starts_with("http://") || to.starts_with("https://")
+}
+
+pub fn validate(rule: &RedirectRule) -> Result<(), String> {
+    if !rule.from.starts_with('/') {
+        return Err(format!("redirect from {:?} must start with '/'", rule.from));
+    }
+    if !rule.to.starts_with('/') && !is_external(&rule.to) {
+        return Err(format!(
+            "redirect to {:?} must start with '/' or be an http(s) URL",
+            rule.to
+        ));
+    }
+    match rule.status {
+        301 | 302 | 307 | 308 => Ok(()),
+        200 if is_external(&rule.to) => Err(format!(
+            "rewrite to {:?} must be a path inside the bucket",
+            rule.to
+        )),
+        200 => Ok(()),
+        status => Err(format!(
+            "redirect status {} must be 200, 301, 302, 307 or 308",
+            status
+        )),
+    }
+}
+
+// Parses a Netlify-style `_redirects` file: one `from to [status]` rule per
+// line, blank lines and `#` comments ignored. A trailing `!` on the status
+// is accepted for compatibility; rules always take precedence here.
+pub fn parse_redirects_file(content: &str) -> Result<Vec<RedirectRule>, String> {
+    let mut rules = Vec::new();
+    for (number, line) in content.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (from, to, status) = match fields[..] {
+            [from, to] => (from, to, 301),
+            [from, to, status] => {
+                let status = status
+                    .trim_end_matches('!')
+                    .parse()
+                    .map_err(|_| format!("line {}: invalid status {:?}", number + 1, status))?;
+                (from, to, status)
+            }
+            _ => return Err(format!("line {}: expected 'from to [status]'", number + 1)),
+        };
+
+        let rule = RedirectRule {
+            from: from.to_string(),
+            to: to.to_string(),
+            status,
+        };
+        validate(&rule).map_err(|e| format!("line {}: {}", number + 1, e))?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+// Matches `path` against a pattern made of literal segments, `:name`
+// placeholders and an optional trailing `*`, which captures the rest of
+// the path as `splat`.
+fn match_pattern(pattern: &str, path: &str) -> Option<HashMap<String, String>> {
+    let pattern = pattern.trim_start_matches('/');
+    let path = path.trim_start_matches('/').trim_end_matches('/');
+    let pattern_segments: Vec<&str> = pattern.split('/').collect();
+    let path_segments: Vec<&str> = if path.is_empty() {
+        Vec::new()
+    } else {
+        path.split('/').collect()
+    };
+
+    let mut captures = HashMap::new();
+    for (i, segment) in pattern_segments.iter().enumerate() {
+        if *segment == "*" && i == pattern_segments.len() - 1 {
+            let rest = path_segments.get(i..).unwrap_or_default().join("/");
+            captures.insert("splat".to_string(), rest);
+            return Some(captures);
+        }
+        if segment.is_empty() && i == pattern_segments.len() - 1 {
+            // Trailing slash in the pattern
+            break;
+        }
+
+        let actual = path_segments.get(i)?;
+        match segment.strip_prefix(':') {
+            Some(name) => {
+                captures.insert(name.to_string(), actual.to_string());
+            }
+            None if segment == actual => {}
+            None => return None,
+        }
+    }
+
+    let expected = pattern_segments
+        .iter()
+        .filter(|segment| !segment.is_empty())
+        .count();
+    (expected == path_segments.len()).then_some(captures)
+}
+
+fn substitute(to: &str, captures: &HashMap<String, String>) -> String {
+    to.split('/')
+        .map(|segment| {
+            segment
+                .strip_prefix(':')
+                .and_then(|name| captures.get(name))
+                .map(String::as_str)
+                .unwrap_or(segment)
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+// Captures come from the decoded path, so they are escaped again before
+// going into a Location; a splat keeps its slashes.
+fn encode_captures(captures: HashMap<String, String>) -> HashMap<String, String> {
+    captures
+        .into_iter()
+        .map(|(name, value)| {
+            let encoded = value
+                .split('/')
+                .map(autoindex::encode_segment)
+                .collect::<Vec<_>>()
+                .join("/");
+            (name, encoded)
+        })
+        .collect()
+}
+
+// Captures may hold decoded slashes, so a rewrite only takes those that
+// cannot climb out of the target directory or hide a protected file.
+fn is_plain_capture(value: &str) -> bool {
+    value.is_empty()
+        || value
+            .split('/')
+            .all(|segment| !matches!(segment, "" | "." | ".."))
+}
+
+// Returns the action of the first rule matching the bucket-relative path.
+pub fn evaluate(rules: &[RedirectRule], file_path: &str) -> Option<Action> {
+    rules.iter().find_map(|rule| {
+        let captures = match_pattern(&rule.from, file_path)?;
+        Some(match StatusCode::from_u16(rule.status) {
+            Ok(StatusCode::OK) if captures.values().all(|value| is_plain_capture(value)) => {
+                Action::Rewrite(substitute(&rule.to, &captures))
+            }
+            Ok(StatusCode::OK) => return None,
+            Ok(status) => {
+                Action::Redirect(status, substitute(&rule.to, &encode_captures(captures)))
+            }
+            Err(_) => return None,
+        })
+    })
+}
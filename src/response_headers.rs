@@ -0,0 +1,81 @@
+use axum::{
+    http::{HeaderName, HeaderValue, StatusCode},
+    response::Response,
+};
+
+use crate::config::HeaderRule;
+
+pub struct HeaderSet {
+    pattern: String,
+    values: Vec<(HeaderName, HeaderValue)>,
+}
+
+// Parses header names and values up front so a typo in config.toml makes
+// the bucket fail to load instead of being silently dropped per request.
+pub fn compile(rules: &[HeaderRule]) -> Result<Vec<HeaderSet>, String> {
+    rules
+        .iter()
+        .map(|rule| {
+            let values = rule
+                .values
+                .iter()
+                .map(|(name, value)| {
+                    let name = HeaderName::from_bytes(name.as_bytes())
+                        .map_err(|_| format!("invalid header name {:?}", name))?;
+                    let value = HeaderValue::from_str(value)
+                        .map_err(|_| format!("invalid value for header {}: {:?}", name, value))?;
+                    Ok((name, value))
+                })
+                .collect::<Result<_, String>>()?;
+            Ok(HeaderSet {
+                pattern: rule.path.clone(),
+                values,
+            })
+        })
+        .collect()
+}
+
+// `*` matches any run of characters, including `/`.
+fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern = pattern.as_bytes();
+    let path = path.as_bytes();
+    let (mut p, mut s) = (0, 0);
+    let mut backtrack = None;
+
+    while s < path.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            backtrack = Some((p, s));
+            p += 1;
+        } else if p < pattern.len() && pattern[p] == path[s] {
+            p += 1;
+            s += 1;
+        } else if let Some((star, matched)) = backtrack {
+            p = star + 1;
+            s = matched + 1;
+            backtrack = Some((star, matched + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+// Applies every matching set in order, later sets overriding earlier ones.
+// Error responses are left alone so e.g. an immutable Cache-Control on
+// `/assets/*` is never attached to a 404.
+pub fn apply(sets: &[HeaderSet], path: &str, response: &mut Response) {
+    let status = response.status();
+    if !status.is_success()
+        && status != StatusCode::NOT_MODIFIED
+        && status != StatusCode::RANGE_NOT_SATISFIABLE
+    {
+        return;
+    }
+
+    let path = format!("/{}", path.trim_start_matches('/'));
+    for set in sets.iter().filter(|set| glob_match(&set.pattern, &path)) {
+        for (name, value) in &set.values {
+            response.headers_mut().insert(name.clone(), value.clone());
+        }
+    }
+}
@@ -0,0 +1,197 @@
+use axum::{Extension, Router, extract::ConnectInfo, serve::Listener};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::{conn::auto::Builder, graceful::GracefulShutdown},
+    service::TowerToHyperService,
+};
+use rustls::{
+    ServerConfig,
+    crypto::{CryptoProvider, ring},
+    server::{ClientHello, ResolvesServerCert},
+    sign::CertifiedKey,
+};
+use rustls_pki_types::{CertificateDer, PrivateKeyDer, pem::PemObject};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio_rustls::TlsAcceptor;
+use tokio_util::sync::CancellationToken;
+use tower::Layer;
+
+use crate::client_cert::{AnyClientCert, ClientCert};
+use crate::config::TlsConfig;
+use crate::listener::PeerAddr;
+use crate::vhost;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+fn load_key(
+    provider: &CryptoProvider,
+    cert: &str,
+    key: &str,
+) -> Result<Arc<CertifiedKey>, Box<dyn std::error::Error>> {
+    let certs = CertificateDer::pem_file_iter(cert)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| format!("{}: {}", cert, e))?;
+    if certs.is_empty() {
+        return Err(format!("{}: no certificates found", cert).into());
+    }
+    let key = PrivateKeyDer::from_pem_file(key).map_err(|e| format!("{}: {}", key, e))?;
+    let key = provider.key_provider.load_private_key(key)?;
+
+    let certified = CertifiedKey::new(certs, key);
+    certified.keys_match()?;
+    Ok(Arc::new(certified))
+}
+
+#[derive(Debug)]
+struct CertStore {
+    default: Arc<CertifiedKey>,
+    sni: Vec<(String, Arc<CertifiedKey>)>,
+}
+
+impl CertStore {
+    fn load(config: &TlsConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let provider = ring::default_provider();
+        let default = load_key(&provider, &config.cert, &config.key)?;
+
+        let mut sni = Vec::new();
+        for entry in &config.sni {
+            let key = load_key(&provider, &entry.cert, &entry.key)?;
+            for host in &entry.hosts {
+                sni.push((vhost::normalize_host(host), key.clone()));
+            }
+        }
+        Ok(Self { default, sni })
+    }
+}
+
+// Picks a certificate by SNI server name, using the same exact-then-longest-
+// wildcard matching as virtual hosts. The store can be swapped at runtime;
+// connections that already completed their handshake are unaffected.
+#[derive(Debug)]
+pub struct CertResolver {
+    config: TlsConfig,
+    store: RwLock<Arc<CertStore>>,
+}
+
+impl CertResolver {
+    pub fn load(config: TlsConfig) -> Result<Self, Box<dyn std::error::Error>> {
+        let store = CertStore::load(&config)?;
+        Ok(Self {
+            config,
+            store: RwLock::new(Arc::new(store)),
+        })
+    }
+
+    // Re-reads every certificate and key from disk. On error the previous
+    // certificates stay in use.
+    pub fn reload(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let store = CertStore::load(&self.config)?;
+        if let Ok(mut current) = self.store.write() {
+            *current = Arc::new(store);
+        }
+        Ok(())
+    }
+}
+
+impl ResolvesServerCert for CertResolver {
+    fn resolve(&self, client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        let store = self.store.read().ok()?.clone();
+        let host = client_hello.server_name().map(vhost::normalize_host);
+        let key = host
+            .and_then(|host| vhost::match_host(&store.sni, &host).map(|(key, _)| key.clone()))
+            .unwrap_or_else(|| store.default.clone());
+        Some(key)
+    }
+}
+
+pub fn server_config(
+    resolver: Arc<CertResolver>,
+) -> Result<Arc<ServerConfig>, Box<dyn std::error::Error>> {
+    let provider = Arc::new(ring::default_provider());
+    let builder = ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = if resolver.config.client_auth {
+        builder.with_client_cert_verifier(Arc::new(AnyClientCert::new(provider)))
+    } else {
+        builder.with_no_client_auth()
+    };
+    let mut config = builder.with_cert_resolver(resolver);
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(Arc::new(config))
+}
+
+// Reloads certificates whenever the process receives SIGHUP. Without TLS
+// there is nothing to reload, but the signal is still caught.
+#[cfg(unix)]
+pub async fn reload_on_sighup(resolver: Option<Arc<CertResolver>>) {
+    use tokio::signal::unix::{SignalKind, signal};
+
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            eprintln!("Failed to install SIGHUP handler: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        let Some(resolver) = &resolver else {
+            println!("Received SIGHUP; TLS is not configured, nothing to reload");
+            continue;
+        };
+        match resolver.reload() {
+            Ok(()) => println!("Reloaded TLS certificates"),
+            Err(e) => eprintln!("Failed to reload TLS certificates: {}", e),
+        }
+    }
+}
+
+// Like `axum::serve`, but terminates TLS first. HTTP/1.1 and HTTP/2 are
+// both served, as negotiated through ALPN. Once `shutdown` is cancelled no
+// new connections are accepted, and this returns when the open ones have
+// finished their requests.
+pub async fn serve_tls<L>(
+    mut listener: L,
+    app: Router,
+    config: Arc<ServerConfig>,
+    shutdown: CancellationToken,
+) where
+    L: Listener,
+    L::Addr: Into<PeerAddr>,
+{
+    let acceptor = TlsAcceptor::from(config);
+    let graceful = GracefulShutdown::new();
+    loop {
+        let (stream, peer): (_, PeerAddr) = tokio::select! {
+            (stream, addr) = listener.accept() => (stream, addr.into()),
+            _ = shutdown.cancelled() => break,
+        };
+        let acceptor = acceptor.clone();
+        let app = app.clone();
+        let watcher = graceful.watcher();
+        tokio::spawn(async move {
+            let stream =
+                match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                    Ok(Ok(stream)) => stream,
+                    _ => return,
+                };
+            let client_cert = ClientCert(Arc::new(
+                stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .map(|chain| chain.iter().map(|cert| cert.clone().into_owned()).collect())
+                    .unwrap_or_default(),
+            ));
+            let service = TowerToHyperService::new(
+                Extension(client_cert).layer(Extension(ConnectInfo(peer)).layer(app)),
+            );
+            let connection = Builder::new(TokioExecutor::new())
+                .serve_connection_with_upgrades(TokioIo::new(stream), service)
+                .into_owned();
+            let _ = watcher.watch(connection).await;
+        });
+    }
+    drop(listener);
+    graceful.shutdown().await;
+}
@@ -0,0 +1,171 @@
+use axum::{
+    extract::{Request, State},
+    http::{Method, StatusCode, header},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use std::sync::Arc;
+
+use crate::{
+    bucket::{BucketManager, LoadedBucket},
+    cors,
+    handlers::serve_from_bucket,
+};
+
+const VHOST_METHODS: &str = "GET, HEAD, OPTIONS";
+
+// Lowercases the host and drops any port and trailing dot.
+pub fn normalize_host(host: &str) -> String {
+    let host = if host.starts_with('[') {
+        // IPv6 literal, keep the brackets
+        host.split_once(']')
+            .map(|(addr, _)| &host[..=addr.len()])
+            .unwrap_or(host)
+    } else {
+        host.split(':').next().unwrap_or(host)
+    };
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+// Finds the best rule matching `host`: an exact name wins, then the
+// wildcard with the longest suffix. Returns the rule's value and the part of
+// the host matched by `*`.
+pub fn match_host<'a, 'h, T>(rules: &'a [(String, T)], host: &'h str) -> Option<(&'a T, &'h str)> {
+    let mut best: Option<(usize, &T, &str)> = None;
+    for (pattern, value) in rules {
+        let (score, subdomain) = if pattern == host {
+            (usize::MAX, "")
+        } else if let Some(suffix) = pattern.strip_prefix("*.")
+            && let Some(subdomain) = host.strip_suffix(suffix)
+            && let Some(subdomain) = subdomain.strip_suffix('.')
+            && !subdomain.is_empty()
+        {
+            (suffix.len(), subdomain)
+        } else {
+            continue;
+        };
+        if best.is_none_or(|(best_score, _, _)| score > best_score) {
+            best = Some((score, value, subdomain));
+        }
+    }
+    best.map(|(_, value, subdomain)| (value, subdomain))
+}
+
+// A `*` bucket takes the name of the matched subdomain, so
+// `"*.example.com" = "*"` maps `docs.example.com` to bucket `docs`.
+pub fn resolve(rules: &[(String, String)], host: &str) -> Option<String> {
+    let (bucket, subdomain) = match_host(rules, host)?;
+    if bucket == "*" {
+        Some(subdomain.to_string())
+    } else {
+        Some(bucket.clone())
+    }
+}
+
+// Decodes like axum's `Path` extractor: `%` not followed by two hex digits
+// stays as it is, and only invalid UTF-8 fails.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok());
+        if bytes[i] == b'%'
+            && let Some(hex) = hex
+        {
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+// The bucket a request's Host header is mapped to, if any.
+pub fn host_bucket(manager: &BucketManager, request: &Request) -> Option<String> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|v| v.to_str().ok())
+        .or_else(|| request.uri().host())?;
+    manager.bucket_for_host(&normalize_host(host))
+}
+
+// The existing bucket a request is for, by Host or by the first path
+// segment (`index` for `/`). The segment is decoded as the routes decode
+// it, so `/%70riv/` is bucket `priv` here too.
+pub fn request_bucket(manager: &BucketManager, request: &Request) -> Option<String> {
+    if let Some(bucket) = host_bucket(manager, request) {
+        return Some(bucket);
+    }
+    let segment = request
+        .uri()
+        .path()
+        .trim_start_matches('/')
+        .split('/')
+        .next()
+        .unwrap_or_default();
+    let name = match percent_decode(segment)? {
+        name if name.is_empty() => "index".to_string(),
+        name => name,
+    };
+    manager.has_bucket(&name).then_some(name)
+}
+
+// The request's bucket, loaded by the first middleware that asks for it and
+// kept in the request's extensions for the layers and handlers after it.
+pub fn load_request_bucket(manager: &BucketManager, request: &mut Request) -> Option<LoadedBucket> {
+    if let Some(loaded) = request.extensions().get::<LoadedBucket>() {
+        return Some(loaded.clone());
+    }
+    let loaded = manager.load_bucket(request_bucket(manager, request)?);
+    request.extensions_mut().insert(loaded.clone());
+    Some(loaded)
+}
+
+// Serves requests whose Host maps to a bucket from that bucket at `/`,
+// ahead of the path-based routes. Such hosts are read-only.
+pub async fn route_host(
+    State(manager): State<Arc<BucketManager>>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(bucket_name) = host_bucket(&manager, &request) else {
+        return next.run(request).await;
+    };
+
+    let bucket = manager.loaded_bucket(request.extensions().get(), &bucket_name);
+    let head = match *request.method() {
+        Method::GET => false,
+        Method::HEAD => true,
+        Method::OPTIONS => {
+            return cors::preflight(bucket.as_deref(), request.headers(), VHOST_METHODS);
+        }
+        _ => {
+            return (
+                StatusCode::METHOD_NOT_ALLOWED,
+                [(header::ALLOW, VHOST_METHODS)],
+            )
+                .into_response();
+        }
+    };
+
+    let Some(file_path) = percent_decode(request.uri().path()) else {
+        return StatusCode::BAD_REQUEST.into_response();
+    };
+    serve_from_bucket(
+        &manager,
+        bucket.as_deref(),
+        "/",
+        file_path.trim_start_matches('/'),
+        request.uri().query(),
+        request.headers(),
+        head,
+    )
+    .await
+}
@@ -12,6 +12,12 @@ struct TestServer {
 
 impl TestServer {
     async fn start() -> Self {
+        Self::start_with(|manager| manager).await
+    }
+
+    async fn start_with(
+        configure: impl FnOnce(stathost::BucketManager) -> stathost::BucketManager,
+    ) -> Self {
         static COUNTER: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
         let id = COUNTER.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         let buckets_dir =
@@ -23,9 +29,9 @@ impl TestServer {
 
         let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
 
-        let buckets_dir_clone = buckets_dir.clone();
+        let manager = configure(stathost::BucketManager::new(buckets_dir.clone()));
         tokio::spawn(async move {
-            run_server(listener, buckets_dir_clone, shutdown_rx).await;
+            run_server(listener, manager, shutdown_rx).await;
         });
 
         sleep(Duration::from_millis(50)).await;
@@ -42,9 +48,13 @@ impl TestServer {
     }
 
     async fn create_bucket(&self, name: &str, token: &str) {
+        self.create_bucket_with_config(name, token, "").await;
+    }
+
+    async fn create_bucket_with_config(&self, name: &str, token: &str, extra: &str) {
         let bucket_path = self.buckets_dir.join(name);
         fs::create_dir_all(&bucket_path).await.unwrap();
-        let config = format!("[auth]\ntoken = \"{}\"", token);
+        let config = format!("[auth]\ntoken = \"{}\"\n{}", token, extra);
         fs::write(bucket_path.join("config.toml"), config)
             .await
             .unwrap();
@@ -58,35 +68,86 @@ impl TestServer {
 
 async fn run_server(
     listener: tokio::net::TcpListener,
-    buckets_dir: PathBuf,
+    manager: stathost::BucketManager,
     shutdown: tokio::sync::oneshot::Receiver<()>,
 ) {
-    use axum::{Router, routing::get};
-    use std::sync::Arc;
+    let app = app(std::sync::Arc::new(manager));
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<stathost::PeerAddr>(),
+    )
+    .with_graceful_shutdown(async {
+        shutdown.await.ok();
+    })
+    .await
+    .unwrap();
+}
 
-    // Import from the main crate
-    let manager = Arc::new(stathost::BucketManager::new(buckets_dir));
+fn app(manager: std::sync::Arc<stathost::BucketManager>) -> axum::Router {
+    use axum::{
+        Router,
+        routing::{get, post},
+    };
 
-    let app = Router::new()
-        .route("/", get(stathost::serve_root_index))
+    Router::new()
+        .route(
+            "/",
+            get(stathost::serve_root_index).head(stathost::head_root_index),
+        )
         .route("/openapi.json", get(stathost::openapi))
-        .route("/{bucket}", get(stathost::serve_bucket_root))
-        .route("/{bucket}/", get(stathost::serve_bucket_root))
-        .route("/{bucket}/_meta/list", get(stathost::list_files))
+        .route(
+            "/{bucket}",
+            get(stathost::serve_bucket_root)
+                .head(stathost::head_bucket_root)
+                .options(stathost::preflight_bucket_root),
+        )
+        .route(
+            "/{bucket}/",
+            get(stathost::serve_bucket_root)
+                .head(stathost::head_bucket_root)
+                .options(stathost::preflight_bucket_root),
+        )
+        .route(
+            "/{bucket}/_meta/list",
+            get(stathost::list_files).options(stathost::preflight_meta),
+        )
+        .route(
+            "/{bucket}/_meta/presign",
+            post(stathost::presign).options(stathost::preflight_presign),
+        )
         .route(
             "/{bucket}/{*path}",
             get(stathost::serve_file)
+                .head(stathost::head_file)
                 .put(stathost::upload_file)
-                .delete(stathost::delete_file),
+                .delete(stathost::delete_file)
+                .options(stathost::preflight_file),
         )
-        .with_state(manager);
-
-    axum::serve(listener, app)
-        .with_graceful_shutdown(async {
-            shutdown.await.ok();
-        })
-        .await
-        .unwrap();
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::route_host,
+        ))
+        .route("/_metrics", get(stathost::serve_metrics))
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::filter_ip,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::rate_limit,
+        ))
+        .layer(stathost::compression_layer(
+            &stathost::config::CompressionConfig::default(),
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::track_metrics,
+        ))
+        .layer(axum::middleware::from_fn_with_state(
+            manager.clone(),
+            stathost::access_log,
+        ))
+        .with_state(manager)
 }
 
 #[tokio::test]
@@ -248,6 +309,27 @@ async fn test_full_workflow() {
         .unwrap();
     assert_eq!(resp.status(), 404);
 
+    // Test: Nor through dot segments the client did not collapse
+    fs::create_dir_all(server.buckets_dir.join("site1/sub"))
+        .await
+        .unwrap();
+    for path in [
+        "/site1/sub/..%2fconfig.toml",
+        "/site1/sub/%2e%2e%2Fconfig.toml",
+        "/site1/.%2fconfig.toml",
+    ] {
+        let resp = client.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 404, "{}", path);
+        let resp = client
+            .put(server.url(path))
+            .header("Authorization", "Bearer token1")
+            .body("hacked")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 400, "{}", path);
+    }
+
     // Test: Cannot upload to config.toml
     let resp = client
         .put(server.url("/site1/config.toml"))
@@ -475,10 +557,65 @@ async fn test_range_requests() {
     assert_eq!(resp.status(), 200);
     assert_eq!(resp.text().await.unwrap(), "0123456789");
 
-    // Multi-range unsupported -> full 200
+    // Multi-range -> multipart/byteranges
+    let resp = client
+        .get(server.url("/vid/clip.bin"))
+        .header("Range", "bytes=0-1,5-6")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    let content_type = resp.headers()["content-type"].to_str().unwrap().to_string();
+    let boundary = content_type
+        .strip_prefix("multipart/byteranges; boundary=")
+        .unwrap()
+        .to_string();
+    let content_length: usize = resp.headers()["content-length"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    let body = resp.text().await.unwrap();
+    assert_eq!(body.len(), content_length);
+    assert_eq!(
+        body,
+        format!(
+            "\r\n--{b}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 0-1/10\r\n\r\n01\
+             \r\n--{b}\r\nContent-Type: application/octet-stream\r\nContent-Range: bytes 5-6/10\r\n\r\n56\
+             \r\n--{b}--\r\n",
+            b = boundary
+        )
+    );
+
+    // Overlapping and adjacent ranges are coalesced into a single part
+    let resp = client
+        .get(server.url("/vid/clip.bin"))
+        .header("Range", "bytes=4-5,0-2,1-3")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers()["content-range"], "bytes 0-5/10");
+    assert_eq!(resp.text().await.unwrap(), "012345");
+
+    // Unsatisfiable parts are dropped from a multi-range request
+    let resp = client
+        .get(server.url("/vid/clip.bin"))
+        .header("Range", "bytes=100-200,8-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.headers()["content-range"], "bytes 8-9/10");
+
+    // Too many ranges -> full 200
+    let many = (0..17)
+        .map(|i| format!("{}-{}", i * 2, i * 2))
+        .collect::<Vec<_>>()
+        .join(",");
     let resp = client
         .get(server.url("/vid/clip.bin"))
-        .header("Range", "bytes=0-1,3-4")
+        .header("Range", format!("bytes={}", many))
         .send()
         .await
         .unwrap();
@@ -553,7 +690,7 @@ async fn test_cleanup_temp_files() {
         .await
         .unwrap();
 
-    stathost::cleanup_temp_files(&dir).await.unwrap();
+    assert_eq!(stathost::cleanup_temp_files(&dir).await.unwrap(), 2);
 
     assert!(dir.join("bucket/keep.txt").exists());
     assert!(!dir.join("bucket/old.ts.ab12-3.stathost-tmp").exists());
@@ -561,3 +698,2868 @@ async fn test_cleanup_temp_files() {
 
     fs::remove_dir_all(&dir).await.unwrap();
 }
+
+#[tokio::test]
+async fn test_conditional_requests() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server.create_bucket("web", "tok").await;
+
+    let resp = client
+        .put(server.url("/web/app.js"))
+        .header("Authorization", "Bearer tok")
+        .body("console.log(1);")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+
+    let resp = client.get(server.url("/web/app.js")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    let last_modified = resp.headers()["last-modified"]
+        .to_str()
+        .unwrap()
+        .to_string();
+    assert!(etag.starts_with('"') && etag.ends_with('"'));
+
+    // Matching If-None-Match -> 304 with validators and no body
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("If-None-Match", &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 304);
+    assert_eq!(resp.headers()["etag"], etag.as_str());
+    assert_eq!(resp.text().await.unwrap(), "");
+
+    // Weak comparison and lists are accepted
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("If-None-Match", format!("\"other\", W/{}", etag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 304);
+
+    // Non-matching If-None-Match wins over If-Modified-Since
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("If-None-Match", "\"other\"")
+        .header("If-Modified-Since", &last_modified)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // If-Modified-Since at the current mtime -> 304
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("If-Modified-Since", &last_modified)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 304);
+
+    // If-Modified-Since in the past -> 200
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("If-Modified-Since", "Thu, 01 Jan 1970 00:00:00 GMT")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // If-Range with the current ETag honours the range
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("Range", "bytes=0-6")
+        .header("If-Range", &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.text().await.unwrap(), "console");
+
+    // If-Range with the current date honours the range
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("Range", "bytes=0-6")
+        .header("If-Range", &last_modified)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+
+    // Stale If-Range -> full body
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("Range", "bytes=0-6")
+        .header("If-Range", "\"stale\"")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "console.log(1);");
+
+    // Weak ETags never satisfy If-Range
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("Range", "bytes=0-6")
+        .header("If-Range", format!("W/{}", etag))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Replacing the file changes the ETag
+    let resp = client
+        .put(server.url("/web/app.js"))
+        .header("Authorization", "Bearer tok")
+        .body("console.log(22);")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+
+    let resp = client
+        .get(server.url("/web/app.js"))
+        .header("If-None-Match", &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_ne!(resp.headers()["etag"], etag.as_str());
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_head_requests() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server.create_bucket("index", "tok").await;
+
+    for path in ["/index/index.html", "/index/data.json"] {
+        let resp = client
+            .put(server.url(path))
+            .header("Authorization", "Bearer tok")
+            .body("{\"hello\": 1}")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 201);
+    }
+
+    let get = client
+        .get(server.url("/index/data.json"))
+        .send()
+        .await
+        .unwrap();
+
+    let resp = client
+        .head(server.url("/index/data.json"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-length"], "12");
+    assert_eq!(resp.headers()["content-type"], "application/json");
+    assert_eq!(resp.headers()["accept-ranges"], "bytes");
+    assert_eq!(resp.headers()["etag"], get.headers()["etag"]);
+    assert_eq!(
+        resp.headers()["last-modified"],
+        get.headers()["last-modified"]
+    );
+    let etag = resp.headers()["etag"].clone();
+    assert_eq!(resp.text().await.unwrap(), "");
+
+    // Conditional HEAD
+    let resp = client
+        .head(server.url("/index/data.json"))
+        .header("If-None-Match", etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 304);
+
+    // Bucket root and root index
+    for path in ["/index", "/index/", "/"] {
+        let resp = client.head(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 200, "HEAD {}", path);
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        assert_eq!(resp.headers()["content-length"], "12");
+    }
+
+    // Missing files, directories and protected paths
+    for path in ["/index/missing.txt", "/index/config.toml", "/nope/file"] {
+        let resp = client.head(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 404, "HEAD {}", path);
+    }
+    fs::create_dir_all(server.buckets_dir.join("index/sub"))
+        .await
+        .unwrap();
+    let resp = client.head(server.url("/index/sub")).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_precompressed_siblings() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server.create_bucket("spa", "tok").await;
+    let dir = server.buckets_dir.join("spa");
+    fs::write(dir.join("app.js"), "plain-js").await.unwrap();
+    fs::write(dir.join("app.js.br"), "brotli-js").await.unwrap();
+    fs::write(dir.join("app.js.gz"), "gzip-js").await.unwrap();
+    fs::write(dir.join("plain.css"), "plain-css").await.unwrap();
+    fs::write(dir.join("orphan.tar.gz"), "archive")
+        .await
+        .unwrap();
+
+    let resp = client
+        .get(server.url("/spa/app.js"))
+        .header("Accept-Encoding", "gzip, br")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-encoding"], "br");
+    assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+    assert_eq!(resp.headers()["content-type"], "text/javascript");
+    assert_eq!(resp.text().await.unwrap(), "brotli-js");
+
+    let resp = client
+        .get(server.url("/spa/app.js"))
+        .header("Accept-Encoding", "br;q=0.5, gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.text().await.unwrap(), "gzip-js");
+
+    let resp = client
+        .head(server.url("/spa/app.js"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert_eq!(resp.headers()["content-length"], "7");
+
+    // No acceptable encoding -> identity, still varies
+    let resp = client
+        .get(server.url("/spa/app.js"))
+        .header("Accept-Encoding", "br;q=0, identity")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.headers()["vary"], "Accept-Encoding");
+    assert_eq!(resp.text().await.unwrap(), "plain-js");
+
+    // Ranges always address the identity file
+    let resp = client
+        .get(server.url("/spa/app.js"))
+        .header("Accept-Encoding", "br")
+        .header("Range", "bytes=0-4")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.text().await.unwrap(), "plain");
+
+    // Files without sidecars don't vary
+    let resp = client
+        .get(server.url("/spa/plain.css"))
+        .header("Accept-Encoding", "gzip, br")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get("vary").is_none());
+    assert!(resp.headers().get("content-encoding").is_none());
+
+    // Sidecars are hidden from listings unless requested
+    let resp = client
+        .get(server.url("/spa/_meta/list"))
+        .header("Authorization", "Bearer tok")
+        .send()
+        .await
+        .unwrap();
+    let mut files: Vec<String> = resp.json().await.unwrap();
+    files.sort();
+    assert_eq!(files, vec!["app.js", "orphan.tar.gz", "plain.css"]);
+
+    let resp = client
+        .get(server.url("/spa/_meta/list?precompressed=true&detail=true"))
+        .header("Authorization", "Bearer tok")
+        .send()
+        .await
+        .unwrap();
+    let entries: Vec<serde_json::Value> = resp.json().await.unwrap();
+    assert_eq!(entries.len(), 5);
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_dynamic_compression() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server
+        .create_bucket_with_config("docs", "tok", "[compression]\nenabled = true")
+        .await;
+    server.create_bucket("plain", "tok").await;
+
+    let html = "<p>compress me</p>".repeat(100);
+    for bucket in ["docs", "plain"] {
+        for (name, body) in [
+            ("page.html", html.as_str()),
+            ("tiny.html", "<p>hi</p>"),
+            ("blob.bin", html.as_str()),
+        ] {
+            fs::write(server.buckets_dir.join(bucket).join(name), body)
+                .await
+                .unwrap();
+        }
+    }
+
+    // Enabled bucket compresses text with the negotiated algorithm
+    for encoding in ["gzip", "br", "zstd"] {
+        let resp = client
+            .get(server.url("/docs/page.html"))
+            .header("Accept-Encoding", encoding)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 200);
+        assert_eq!(resp.headers()["content-encoding"], encoding);
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        assert!(resp.headers().get("content-length").is_none());
+        let body = resp.bytes().await.unwrap();
+        assert!(body.len() < html.len());
+    }
+
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(&resp.bytes().await.unwrap()[..2], &[0x1f, 0x8b]);
+
+    // Without Accept-Encoding the body is untouched but varies
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.headers()["vary"], "accept-encoding");
+    let etag = resp.headers()["etag"].to_str().unwrap().to_string();
+    assert!(etag.starts_with('"'));
+    assert_eq!(resp.text().await.unwrap(), html);
+
+    // Encoded bodies get a weak ETag, which still revalidates but no
+    // longer satisfies If-Range
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    let weak = resp.headers()["etag"].to_str().unwrap().to_string();
+    assert_eq!(weak, format!("W/{}", etag));
+
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .header("If-None-Match", &weak)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 304);
+
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Range", "bytes=0-9")
+        .header("If-Range", &weak)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), html);
+
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Range", "bytes=0-9")
+        .header("If-Range", &etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+
+    // Precompressed siblings keep their own strong ETag
+    fs::write(server.buckets_dir.join("docs/page.html.gz"), "gz")
+        .await
+        .unwrap();
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+    assert!(resp.headers()["etag"].to_str().unwrap().starts_with('"'));
+    assert_eq!(resp.text().await.unwrap(), "gz");
+    fs::remove_file(server.buckets_dir.join("docs/page.html.gz"))
+        .await
+        .unwrap();
+
+    // Small files, non-text types, ranges and HEAD are never compressed
+    for path in ["/docs/tiny.html", "/docs/blob.bin"] {
+        let resp = client
+            .get(server.url(path))
+            .header("Accept-Encoding", "gzip")
+            .send()
+            .await
+            .unwrap();
+        assert!(resp.headers().get("content-encoding").is_none(), "{}", path);
+    }
+
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .header("Range", "bytes=0-9")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.text().await.unwrap(), "<p>compres");
+
+    let resp = client
+        .get(server.url("/docs/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .header("Range", "bytes=0-1,5-6")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert!(resp.headers().get("content-encoding").is_none());
+
+    let resp = client
+        .head(server.url("/docs/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(
+        resp.headers()["content-length"],
+        html.len().to_string().as_str()
+    );
+
+    // Server default is off, so other buckets are served as-is
+    let resp = client
+        .get(server.url("/plain/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get("content-encoding").is_none());
+    assert_eq!(resp.text().await.unwrap(), html);
+
+    // Per-bucket MIME allow-list replaces the server list
+    fs::write(
+        server.buckets_dir.join("plain/config.toml"),
+        "[auth]\ntoken = \"tok\"\n[compression]\nenabled = true\nmime_types = [\"application/*\"]",
+    )
+    .await
+    .unwrap();
+    let resp = client
+        .get(server.url("/plain/page.html"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get("content-encoding").is_none());
+    let resp = client
+        .get(server.url("/plain/blob.bin"))
+        .header("Accept-Encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["content-encoding"], "gzip");
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_autoindex() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server
+        .create_bucket_with_config("artifacts", "tok", "[serve]\nautoindex = true")
+        .await;
+    server.create_bucket("closed", "tok").await;
+
+    let dir = server.buckets_dir.join("artifacts");
+    fs::create_dir_all(dir.join("builds/v1")).await.unwrap();
+    fs::write(dir.join("builds/big.tar"), "x".repeat(100))
+        .await
+        .unwrap();
+    fs::write(dir.join("builds/a <b>.txt"), "y").await.unwrap();
+    fs::write(dir.join("builds/a <b>.txt.gz"), "z")
+        .await
+        .unwrap();
+    fs::write(dir.join("builds/up.bin.1-2.stathost-tmp"), "partial")
+        .await
+        .unwrap();
+    fs::create_dir_all(server.buckets_dir.join("closed/sub"))
+        .await
+        .unwrap();
+
+    // Bucket root lists the top level, without config.toml
+    let resp = client.get(server.url("/artifacts/")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(
+        resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/html")
+    );
+    assert_eq!(resp.headers()["vary"], "Accept");
+    let html = resp.text().await.unwrap();
+    assert!(html.contains("<a href=\"builds/\">builds/</a>"));
+    assert!(!html.contains("config.toml"));
+    assert!(!html.contains("href=\"../\""));
+
+    // Subdirectory: escaped names, hidden temp and sidecar files
+    let resp = client
+        .get(server.url("/artifacts/builds/"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    let html = resp.text().await.unwrap();
+    assert!(html.contains("href=\"../\""));
+    assert!(html.contains("<a href=\"a%20%3Cb%3E.txt\">a &lt;b&gt;.txt</a>"));
+    assert!(html.contains("<a href=\"big.tar\">big.tar</a></td><td>100</td>"));
+    assert!(!html.contains("stathost-tmp"));
+    assert!(!html.contains(".gz"));
+    // Directories first, then files by name
+    let v1 = html.find("v1/").unwrap();
+    let a = html.find("a &lt;b&gt;").unwrap();
+    let big = html.find("big.tar").unwrap();
+    assert!(v1 < a && a < big);
+
+    // Sort by size descending
+    let resp = client
+        .get(server.url("/artifacts/builds/?sort=size&order=desc"))
+        .send()
+        .await
+        .unwrap();
+    let html = resp.text().await.unwrap();
+    assert!(html.find("big.tar").unwrap() < html.find("a &lt;b&gt;").unwrap());
+
+    // JSON variant
+    let resp = client
+        .get(server.url("/artifacts/builds/"))
+        .header("Accept", "application/json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["vary"], "Accept");
+    let entries: Vec<serde_json::Value> = resp.json().await.unwrap();
+    let paths: Vec<&str> = entries
+        .iter()
+        .map(|e| e["path"].as_str().unwrap())
+        .collect();
+    assert_eq!(
+        paths,
+        vec!["builds/v1/", "builds/a <b>.txt", "builds/big.tar"]
+    );
+    assert_eq!(entries[2]["size"], 100);
+
+    // Directory without trailing slash redirects, as does the bucket itself
+    let no_redirect = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+    fs::create_dir_all(dir.join("builds/a?b")).await.unwrap();
+    for (path, location) in [
+        ("/artifacts/builds", "builds/"),
+        ("/artifacts/builds/a%3Fb", "a%3Fb/"),
+        ("/artifacts", "artifacts/"),
+        ("/artifacts?sort=size", "artifacts/?sort=size"),
+    ] {
+        let resp = no_redirect.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 308, "{}", path);
+        assert_eq!(resp.headers()["location"], location, "{}", path);
+    }
+    let resp = no_redirect
+        .head(server.url("/artifacts"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 308);
+    let resp = no_redirect
+        .get(server.url("/nonexistent"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    // index.html still wins
+    fs::write(dir.join("builds/v1/index.html"), "<h1>v1</h1>")
+        .await
+        .unwrap();
+    let resp = client
+        .get(server.url("/artifacts/builds/v1/"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), "<h1>v1</h1>");
+
+    // Protected and missing directories
+    for path in ["/artifacts/_meta/", "/artifacts/missing/"] {
+        let resp = client.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 404, "{}", path);
+    }
+
+    // Buckets that don't opt in keep returning 404
+    let resp = client.get(server.url("/closed/sub/")).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_spa_fallback() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server
+        .create_bucket_with_config("app", "tok", "[serve]\nspa_fallback = \"index.html\"")
+        .await;
+    let dir = server.buckets_dir.join("app");
+    fs::create_dir_all(dir.join("assets")).await.unwrap();
+    fs::write(dir.join("index.html"), "<div id=root></div>")
+        .await
+        .unwrap();
+    fs::write(dir.join("assets/app.js"), "boot()")
+        .await
+        .unwrap();
+
+    // Deep links serve the fallback document with 200
+    for path in ["/app/settings/profile", "/app/settings/", "/app/users/42"] {
+        let resp = client.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 200, "{}", path);
+        assert_eq!(resp.headers()["content-type"], "text/html");
+        assert_eq!(resp.text().await.unwrap(), "<div id=root></div>");
+    }
+
+    let resp = client
+        .head(server.url("/app/settings/profile"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["content-type"], "text/html");
+
+    // Real files still win
+    let resp = client
+        .get(server.url("/app/assets/app.js"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), "boot()");
+
+    // Missing assets and protected paths are real 404s
+    for path in [
+        "/app/assets/missing.js",
+        "/app/favicon.ico",
+        "/app/config.toml",
+        "/app/_meta/other",
+    ] {
+        let resp = client.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 404, "{}", path);
+    }
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_error_pages() {
+    let server = TestServer::start_with(|manager| {
+        manager.with_error_template("<h1>{{status}} {{reason}}</h1>".to_string())
+    })
+    .await;
+    let client = reqwest::Client::new();
+
+    server.create_bucket("site", "tok").await;
+    server
+        .create_bucket_with_config("custom", "tok", "[errors]\n404 = \"errors/missing.html\"")
+        .await;
+    fs::write(server.buckets_dir.join("site/404.html"), "<p>site 404</p>")
+        .await
+        .unwrap();
+    fs::create_dir_all(server.buckets_dir.join("custom/errors"))
+        .await
+        .unwrap();
+    fs::write(
+        server.buckets_dir.join("custom/errors/missing.html"),
+        "<p>custom missing</p>",
+    )
+    .await
+    .unwrap();
+    fs::write(server.buckets_dir.join("custom/404.html"), "<p>unused</p>")
+        .await
+        .unwrap();
+
+    // Bucket-supplied 404.html
+    let resp = client
+        .get(server.url("/site/nope.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.headers()["content-type"], "text/html");
+    assert_eq!(resp.text().await.unwrap(), "<p>site 404</p>");
+
+    // Configured page name overrides the default
+    let resp = client
+        .get(server.url("/custom/nope.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.text().await.unwrap(), "<p>custom missing</p>");
+
+    // Protected paths get the page too
+    let resp = client
+        .get(server.url("/site/config.toml"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.text().await.unwrap(), "<p>site 404</p>");
+
+    // Unknown bucket falls back to the server template
+    let resp = client
+        .get(server.url("/missing/file.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.text().await.unwrap(), "<h1>404 Not Found</h1>");
+
+    // Statuses without a bucket page use the template, keeping headers
+    let resp = client
+        .get(server.url("/site/404.html"))
+        .header("Range", "bytes=1000-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 416);
+    assert_eq!(resp.headers()["content-range"], "bytes */15");
+    assert_eq!(
+        resp.text().await.unwrap(),
+        "<h1>416 Range Not Satisfiable</h1>"
+    );
+
+    // HEAD gets the status and headers without a body
+    let resp = client
+        .head(server.url("/site/nope.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.headers()["content-type"], "text/html");
+
+    // The error page itself is still a normal file
+    let resp = client
+        .get(server.url("/site/404.html"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_redirects() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    server
+        .create_bucket_with_config(
+            "blog",
+            "tok",
+            r#"
+[serve]
+redirects_file = "_redirects"
+
+[[redirects]]
+from = "/old-page"
+to = "/new-page.html"
+
+[[redirects]]
+from = "/news/:year/:month/*"
+to = "/posts/:year/:month/:splat"
+status = 302
+
+[[redirects]]
+from = "/docs/*"
+to = "https://docs.example.com/:splat"
+status = 308
+
+[[redirects]]
+from = "/app/*"
+to = "/app/index.html"
+status = 200
+
+[[redirects]]
+from = "/files/*"
+to = "/static/:splat"
+status = 200
+"#,
+        )
+        .await;
+    let dir = server.buckets_dir.join("blog");
+    fs::create_dir_all(dir.join("app")).await.unwrap();
+    fs::write(dir.join("app/index.html"), "<app/>")
+        .await
+        .unwrap();
+    fs::write(dir.join("shadowed.html"), "real file")
+        .await
+        .unwrap();
+    fs::write(
+        dir.join("_redirects"),
+        "# legacy\n/legacy   /new-page.html   307\n/shadowed.html /new-page.html 301!\n",
+    )
+    .await
+    .unwrap();
+
+    // Exact match, default 301, query preserved
+    let resp = client
+        .get(server.url("/blog/old-page?ref=x"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 301);
+    assert_eq!(resp.headers()["location"], "/blog/new-page.html?ref=x");
+
+    // Placeholders and splat
+    let resp = client
+        .get(server.url("/blog/news/2024/05/launch/day-1"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 302);
+    assert_eq!(
+        resp.headers()["location"],
+        "/blog/posts/2024/05/launch/day-1"
+    );
+
+    // External target
+    let resp = client
+        .get(server.url("/blog/docs/guide/intro"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 308);
+    assert_eq!(
+        resp.headers()["location"],
+        "https://docs.example.com/guide/intro"
+    );
+
+    // Captured segments are escaped again in Location
+    for (path, location) in [
+        (
+            "/blog/docs/a%3Fb%20c/d%23e",
+            "https://docs.example.com/a%3Fb%20c/d%23e",
+        ),
+        ("/blog/docs/a%0db", "https://docs.example.com/a%0Db"),
+        ("/blog/docs/caf%C3%A9", "https://docs.example.com/caf%C3%A9"),
+        (
+            "/blog/news/2024/0%3B5/x%26y",
+            "/blog/posts/2024/0%3B5/x%26y",
+        ),
+    ] {
+        let resp = client.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.headers()["location"], location, "{}", path);
+    }
+
+    // Rewrite serves another file with 200
+    let resp = client
+        .get(server.url("/blog/app/settings/profile"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "<app/>");
+
+    // Rewritten captures cannot climb out of the target directory
+    fs::create_dir_all(dir.join("static/sub")).await.unwrap();
+    fs::write(dir.join("static/sub/a.txt"), "static a")
+        .await
+        .unwrap();
+    let resp = client
+        .get(server.url("/blog/files/sub/a.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "static a");
+    for path in [
+        "/blog/files/..%2fconfig.toml",
+        "/blog/files/sub%2f..%2f..%2fconfig.toml",
+        "/blog/files/.%2f..%2fconfig.toml",
+        "/blog/files/sub%2f%2fa.txt",
+    ] {
+        let resp = client.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 404, "{}", path);
+    }
+
+    // Rules from the _redirects file, evaluated before existing files
+    let resp = client.get(server.url("/blog/legacy")).send().await.unwrap();
+    assert_eq!(resp.status(), 307);
+    assert_eq!(resp.headers()["location"], "/blog/new-page.html");
+
+    let resp = client
+        .head(server.url("/blog/shadowed.html"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 301);
+
+    // Non-matching paths are untouched
+    let resp = client
+        .get(server.url("/blog/news/2024"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    // Invalid rules make the bucket fail to load
+    server
+        .create_bucket_with_config(
+            "broken",
+            "tok",
+            "[[redirects]]\nfrom = \"/a\"\nto = \"/b\"\nstatus = 404",
+        )
+        .await;
+    fs::write(server.buckets_dir.join("broken/b"), "b")
+        .await
+        .unwrap();
+    let resp = client.get(server.url("/broken/b")).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_custom_headers() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server
+        .create_bucket_with_config(
+            "site",
+            "tok",
+            r#"
+[[headers]]
+for = "/assets/*"
+[headers.values]
+Cache-Control = "public, max-age=31536000, immutable"
+
+[[headers]]
+for = "*.html"
+[headers.values]
+Cache-Control = "no-cache"
+Content-Security-Policy = "default-src 'self'"
+X-Frame-Options = "DENY"
+"#,
+        )
+        .await;
+    let dir = server.buckets_dir.join("site");
+    fs::create_dir_all(dir.join("assets")).await.unwrap();
+    fs::write(dir.join("assets/app.js"), "console.log(1);")
+        .await
+        .unwrap();
+    fs::write(dir.join("index.html"), "<h1>home</h1>")
+        .await
+        .unwrap();
+
+    let resp = client
+        .get(server.url("/site/assets/app.js"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(
+        resp.headers()["cache-control"],
+        "public, max-age=31536000, immutable"
+    );
+    assert!(resp.headers().get("x-frame-options").is_none());
+    let etag = resp.headers()["etag"].clone();
+
+    // Directory index matches *.html through the served file
+    let resp = client.get(server.url("/site/")).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["cache-control"], "no-cache");
+    assert_eq!(resp.headers()["x-frame-options"], "DENY");
+    assert_eq!(
+        resp.headers()["content-security-policy"],
+        "default-src 'self'"
+    );
+
+    // Partial, unsatisfiable and not-modified responses carry them too
+    let resp = client
+        .get(server.url("/site/assets/app.js"))
+        .header("Range", "bytes=0-3")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert_eq!(
+        resp.headers()["cache-control"],
+        "public, max-age=31536000, immutable"
+    );
+
+    let resp = client
+        .get(server.url("/site/assets/app.js"))
+        .header("Range", "bytes=1000-")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 416);
+    assert!(resp.headers().contains_key("cache-control"));
+
+    let resp = client
+        .get(server.url("/site/assets/app.js"))
+        .header("If-None-Match", etag)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 304);
+    assert!(resp.headers().contains_key("cache-control"));
+
+    // Errors never get them
+    let resp = client
+        .get(server.url("/site/assets/missing.js"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert!(resp.headers().get("cache-control").is_none());
+
+    // An invalid header name makes the bucket fail to load
+    server
+        .create_bucket_with_config(
+            "broken",
+            "tok",
+            "[[headers]]\nfor = \"/*\"\n[headers.values]\n\"Bad Header\" = \"x\"\n",
+        )
+        .await;
+    fs::write(server.buckets_dir.join("broken/a.txt"), "a")
+        .await
+        .unwrap();
+    let resp = client
+        .get(server.url("/broken/a.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_cors() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+    let origin = "https://app.example.com";
+
+    server
+        .create_bucket_with_config(
+            "api",
+            "tok",
+            r#"
+[cors]
+allowed_origins = ["https://app.example.com"]
+allowed_methods = ["GET", "HEAD", "PUT"]
+allowed_headers = ["Authorization", "Content-Type"]
+expose_headers = ["ETag", "Content-Range"]
+max_age = 600
+allow_credentials = true
+"#,
+        )
+        .await;
+    server
+        .create_bucket_with_config("fonts", "tok", "[cors]\nallowed_origins = [\"*\"]\n")
+        .await;
+    server.create_bucket("plain", "tok").await;
+    for bucket in ["api", "fonts", "plain"] {
+        fs::write(server.buckets_dir.join(bucket).join("data.json"), "{}")
+            .await
+            .unwrap();
+    }
+
+    // Actual requests from an allowed origin
+    let resp = client
+        .get(server.url("/api/data.json"))
+        .header("Origin", origin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+    assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+    assert_eq!(
+        resp.headers()["access-control-expose-headers"],
+        "ETag, Content-Range"
+    );
+    assert!(
+        resp.headers()
+            .get_all("vary")
+            .iter()
+            .any(|v| v.to_str().unwrap().contains("Origin"))
+    );
+
+    // Errors carry CORS headers too
+    let resp = client
+        .get(server.url("/api/missing.json"))
+        .header("Origin", origin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+
+    let resp = client
+        .put(server.url("/api/upload.json"))
+        .header("Origin", origin)
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+
+    let resp = client
+        .put(server.url("/api/upload.json"))
+        .header("Origin", origin)
+        .header("Authorization", "Bearer tok")
+        .body("{}")
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.status().is_success());
+    assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+
+    let resp = client
+        .get(server.url("/api/_meta/list"))
+        .header("Origin", origin)
+        .header("Authorization", "Bearer tok")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+
+    // Unknown origins get no CORS headers
+    let resp = client
+        .get(server.url("/api/data.json"))
+        .header("Origin", "https://evil.example.com")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+
+    // Preflight
+    let resp = client
+        .request(reqwest::Method::OPTIONS, server.url("/api/upload.json"))
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "PUT")
+        .header(
+            "Access-Control-Request-Headers",
+            "authorization, content-type",
+        )
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 204);
+    assert_eq!(resp.headers()["access-control-allow-origin"], origin);
+    assert_eq!(
+        resp.headers()["access-control-allow-methods"],
+        "GET, HEAD, PUT"
+    );
+    assert_eq!(
+        resp.headers()["access-control-allow-headers"],
+        "Authorization, Content-Type"
+    );
+    assert_eq!(resp.headers()["access-control-max-age"], "600");
+    assert_eq!(resp.headers()["access-control-allow-credentials"], "true");
+
+    for (path, method, header) in [
+        ("/api/upload.json", "DELETE", ""),
+        ("/api/upload.json", "PUT", "x-custom"),
+        ("/api/_meta/list", "PUT", ""),
+        ("/plain/data.json", "GET", ""),
+    ] {
+        let mut req = client
+            .request(reqwest::Method::OPTIONS, server.url(path))
+            .header("Origin", origin)
+            .header("Access-Control-Request-Method", method);
+        if !header.is_empty() {
+            req = req.header("Access-Control-Request-Headers", header);
+        }
+        let resp = req.send().await.unwrap();
+        assert_eq!(resp.status(), 403, "{} {} {}", path, method, header);
+    }
+
+    let resp = client
+        .request(reqwest::Method::OPTIONS, server.url("/api/_meta/list"))
+        .header("Origin", origin)
+        .header("Access-Control-Request-Method", "GET")
+        .header("Access-Control-Request-Headers", "Authorization")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 204);
+    assert_eq!(resp.headers()["allow"], "GET, OPTIONS");
+
+    let resp = client
+        .request(reqwest::Method::OPTIONS, server.url("/api/data.json"))
+        .header("Origin", "https://evil.example.com")
+        .header("Access-Control-Request-Method", "GET")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    // Plain OPTIONS just lists the methods
+    let resp = client
+        .request(reqwest::Method::OPTIONS, server.url("/plain/"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 204);
+    assert_eq!(resp.headers()["allow"], "GET, HEAD, OPTIONS");
+
+    // Wildcard origin without credentials
+    let resp = client
+        .get(server.url("/fonts/data.json"))
+        .header("Origin", origin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["access-control-allow-origin"], "*");
+    assert!(
+        resp.headers()
+            .get("access-control-allow-credentials")
+            .is_none()
+    );
+
+    // Buckets without [cors] are unchanged
+    let resp = client
+        .get(server.url("/plain/data.json"))
+        .header("Origin", origin)
+        .send()
+        .await
+        .unwrap();
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+
+    // Credentials cannot be offered to every origin
+    server
+        .create_bucket_with_config(
+            "open",
+            "tok",
+            "[cors]\nallowed_origins = [\"*\"]\nallow_credentials = true\n",
+        )
+        .await;
+    fs::write(server.buckets_dir.join("open/data.json"), "{}")
+        .await
+        .unwrap();
+    let resp = client
+        .get(server.url("/open/data.json"))
+        .header("Origin", "https://evil.example")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+    assert!(resp.headers().get("access-control-allow-origin").is_none());
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_virtual_hosts() {
+    let server = TestServer::start_with(|m| {
+        m.with_hosts(
+            [
+                ("Docs.Example.com", "docs"),
+                ("*.sites.example.com", "*"),
+                ("*.example.com", "fallback"),
+            ]
+            .into_iter()
+            .map(|(host, bucket)| (host.to_string(), bucket.to_string()))
+            .collect(),
+        )
+    })
+    .await;
+    let client = reqwest::Client::builder()
+        .redirect(reqwest::redirect::Policy::none())
+        .build()
+        .unwrap();
+
+    server
+        .create_bucket_with_config(
+            "docs",
+            "tok",
+            "[[redirects]]\nfrom = \"/old\"\nto = \"/guide/\"\n",
+        )
+        .await;
+    server.create_bucket("alpha", "tok").await;
+    server.create_bucket("fallback", "tok").await;
+    let dir = &server.buckets_dir;
+    fs::create_dir_all(dir.join("blog")).await.unwrap();
+    fs::write(
+        dir.join("blog/config.toml"),
+        "domains = [\"blog.example.org\", \"*.blog.example.org\"]\n\n[auth]\ntoken = \"tok\"\n",
+    )
+    .await
+    .unwrap();
+    fs::create_dir_all(dir.join("docs/guide")).await.unwrap();
+    fs::write(dir.join("docs/index.html"), "docs home")
+        .await
+        .unwrap();
+    fs::write(dir.join("docs/guide/index.html"), "guide")
+        .await
+        .unwrap();
+    fs::write(dir.join("docs/a b.txt"), "spaced").await.unwrap();
+    fs::write(dir.join("alpha/index.html"), "alpha")
+        .await
+        .unwrap();
+    fs::write(dir.join("fallback/index.html"), "fallback")
+        .await
+        .unwrap();
+    fs::write(dir.join("blog/post.html"), "post").await.unwrap();
+
+    let get = |host: &str, path: &str| client.get(server.url(path)).header("Host", host).send();
+
+    let resp = get("docs.example.com", "/").await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "docs home");
+
+    let resp = get("DOCS.example.com:8080", "/guide/").await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "guide");
+
+    let resp = get("docs.example.com", "/a%20b.txt").await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "spaced");
+
+    // Redirects are relative to the host root
+    let resp = get("docs.example.com", "/old").await.unwrap();
+    assert_eq!(resp.status(), 301);
+    assert_eq!(resp.headers()["location"], "/guide/");
+
+    // Path-based bucket names do not apply on a virtual host
+    let resp = get("docs.example.com", "/docs/index.html").await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    // Wildcards: `*` bucket takes the subdomain name, longest suffix wins
+    let resp = get("alpha.sites.example.com", "/").await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "alpha");
+    let resp = get("other.example.com", "/").await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "fallback");
+
+    // Domains declared in the bucket config
+    let resp = get("blog.example.org", "/post.html").await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "post");
+    let resp = get("www.blog.example.org", "/post.html").await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "post");
+
+    // Virtual hosts are read-only
+    let resp = client
+        .put(server.url("/new.txt"))
+        .header("Host", "docs.example.com")
+        .header("Authorization", "Bearer tok")
+        .body("x")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 405);
+    assert_eq!(resp.headers()["allow"], "GET, HEAD, OPTIONS");
+
+    // Unmapped hosts use the path-based routes
+    let resp = get("unknown.example.net", "/docs/index.html")
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), "docs home");
+    let resp = client
+        .get(server.url("/blog/post.html"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), "post");
+
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_tls() {
+    use rustls_pki_types::{CertificateDer, pem::PemObject};
+    use std::sync::Arc;
+
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls");
+    let der = |name: &str| {
+        CertificateDer::from_pem_file(fixtures.join(format!("{}.pem", name)))
+            .unwrap()
+            .to_vec()
+    };
+
+    let buckets_dir = PathBuf::from(format!("/tmp/stathost-tls-test-{}", std::process::id()));
+    let cert_dir = buckets_dir.with_extension("certs");
+    fs::create_dir_all(&cert_dir).await.unwrap();
+    fs::copy(fixtures.join("default.pem"), cert_dir.join("cert.pem"))
+        .await
+        .unwrap();
+    fs::copy(fixtures.join("default.key"), cert_dir.join("key.pem"))
+        .await
+        .unwrap();
+
+    let config = stathost::config::TlsConfig {
+        cert: cert_dir.join("cert.pem").to_string_lossy().to_string(),
+        key: cert_dir.join("key.pem").to_string_lossy().to_string(),
+        sni: vec![stathost::config::SniCertificate {
+            hosts: vec!["docs.example.com".to_string()],
+            cert: fixtures.join("docs.pem").to_string_lossy().to_string(),
+            key: fixtures.join("docs.key").to_string_lossy().to_string(),
+        }],
+        client_auth: false,
+    };
+    let resolver = Arc::new(stathost::CertResolver::load(config).unwrap());
+    let server_config = stathost::server_config(resolver.clone()).unwrap();
+
+    fs::create_dir_all(buckets_dir.join("site")).await.unwrap();
+    fs::write(
+        buckets_dir.join("site/config.toml"),
+        "[auth]\ntoken = \"tok\"\n",
+    )
+    .await
+    .unwrap();
+    fs::write(buckets_dir.join("site/a.txt"), "secure")
+        .await
+        .unwrap();
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let manager = Arc::new(stathost::BucketManager::new(buckets_dir.clone()));
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let server = tokio::spawn(stathost::serve_tls(
+        listener,
+        app(manager),
+        server_config,
+        shutdown.clone(),
+    ));
+
+    // A fresh client per request, so every request does its own handshake
+    let fetch = |host: &'static str| async move {
+        let client = reqwest::Client::builder()
+            .use_rustls_tls()
+            .danger_accept_invalid_certs(true)
+            .tls_info(true)
+            .resolve(host, addr)
+            .build()
+            .unwrap();
+        let resp = client
+            .get(format!("https://{}:{}/site/a.txt", host, addr.port()))
+            .send()
+            .await
+            .unwrap();
+        let version = resp.version();
+        let cert = resp
+            .extensions()
+            .get::<reqwest::tls::TlsInfo>()
+            .and_then(|info| info.peer_certificate())
+            .map(<[u8]>::to_vec);
+        (version, cert, resp.text().await.unwrap())
+    };
+
+    let (version, cert, body) = fetch("localhost").await;
+    assert_eq!(version, reqwest::Version::HTTP_2);
+    assert_eq!(body, "secure");
+    assert_eq!(cert, Some(der("default")));
+
+    // SNI picks the virtual host's certificate
+    let (_, cert, body) = fetch("docs.example.com").await;
+    assert_eq!(body, "secure");
+    assert_eq!(cert, Some(der("docs")));
+
+    // Reloading picks up renewed files
+    fs::copy(fixtures.join("renewed.pem"), cert_dir.join("cert.pem"))
+        .await
+        .unwrap();
+    fs::copy(fixtures.join("renewed.key"), cert_dir.join("key.pem"))
+        .await
+        .unwrap();
+    resolver.reload().unwrap();
+    let (_, cert, _) = fetch("localhost").await;
+    assert_eq!(cert, Some(der("renewed")));
+
+    // A broken file fails the reload and keeps the current certificate
+    fs::write(cert_dir.join("cert.pem"), "not a certificate")
+        .await
+        .unwrap();
+    assert!(resolver.reload().is_err());
+    let (_, cert, _) = fetch("localhost").await;
+    assert_eq!(cert, Some(der("renewed")));
+
+    shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap();
+    let _ = fs::remove_dir_all(&buckets_dir).await;
+    let _ = fs::remove_dir_all(&cert_dir).await;
+}
+
+#[tokio::test]
+async fn test_client_certs() {
+    use std::sync::Arc;
+
+    let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/tls");
+    let fixture = |name: &str| fixtures.join(name).to_string_lossy().to_string();
+    let config = stathost::config::TlsConfig {
+        cert: fixture("default.pem"),
+        key: fixture("default.key"),
+        sni: Vec::new(),
+        client_auth: true,
+    };
+    let resolver = Arc::new(stathost::CertResolver::load(config).unwrap());
+    let server_config = stathost::server_config(resolver).unwrap();
+
+    let buckets_dir = PathBuf::from(format!("/tmp/stathost-mtls-test-{}", std::process::id()));
+    let ca = fixture("client-ca.pem");
+    for (name, client_certs) in [
+        ("farm", format!("ca = \"{}\"\nsubjects = [\"build-farm\"]", ca)),
+        (
+            "other",
+            format!("ca = \"{}\"\nsubjects = [\"O=Example, CN=deployer\"]", ca),
+        ),
+        (
+            "pinned",
+            // The self-signed `rogue` certificate
+            "fingerprints = [\"sha256:F1:7C:6D:0E:61:F6:8A:E6:79:B4:3F:D0:2E:1F:CD:A9:57:D7:65:37:C7:5C:DD:CC:77:9C:4A:81:C6:65:72:AE\"]".to_string(),
+        ),
+    ] {
+        fs::create_dir_all(buckets_dir.join(name)).await.unwrap();
+        fs::write(
+            buckets_dir.join(name).join("config.toml"),
+            format!(
+                "[auth]\ntoken = \"tok\"\n\n[auth.client_certs]\n{}\n",
+                client_certs
+            ),
+        )
+        .await
+        .unwrap();
+    }
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let manager = Arc::new(stathost::BucketManager::new(buckets_dir.clone()));
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let server = tokio::spawn(stathost::serve_tls(
+        listener,
+        app(manager),
+        server_config,
+        shutdown.clone(),
+    ));
+
+    let client = |identity: Option<&str>| {
+        let mut builder = reqwest::Client::builder()
+            .use_rustls_tls()
+            .danger_accept_invalid_certs(true);
+        if let Some(name) = identity {
+            let mut pem = std::fs::read(fixtures.join(format!("{}.pem", name))).unwrap();
+            pem.extend(std::fs::read(fixtures.join(format!("{}.key", name))).unwrap());
+            builder = builder.identity(reqwest::Identity::from_pem(&pem).unwrap());
+        }
+        builder.build().unwrap()
+    };
+    let url = |path: &str| format!("https://localhost:{}{}", addr.port(), path);
+    let put = |client: &reqwest::Client, path: &str| client.put(url(path)).body("a").send();
+
+    let farm = client(Some("client"));
+    let rogue = client(Some("rogue"));
+    let anonymous = client(None);
+
+    // Issued by the bucket's CA with an accepted subject
+    assert_eq!(put(&farm, "/farm/a.txt").await.unwrap().status(), 201);
+    let resp = farm.delete(url("/farm/a.txt")).send().await.unwrap();
+    assert_eq!(resp.status(), 204);
+    // Same subject, but self-signed
+    assert_eq!(put(&rogue, "/farm/a.txt").await.unwrap().status(), 401);
+    // Right CA, wrong subject
+    assert_eq!(put(&farm, "/other/a.txt").await.unwrap().status(), 401);
+    // Pinned by fingerprint
+    assert_eq!(put(&rogue, "/pinned/a.txt").await.unwrap().status(), 201);
+    assert_eq!(put(&farm, "/pinned/a.txt").await.unwrap().status(), 401);
+
+    // Clients without a certificate can still connect and use tokens
+    assert_eq!(put(&anonymous, "/farm/a.txt").await.unwrap().status(), 401);
+    let resp = anonymous
+        .put(url("/farm/a.txt"))
+        .header("Authorization", "Bearer tok")
+        .body("a")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    let resp = anonymous.get(url("/farm/a.txt")).send().await.unwrap();
+    assert_eq!(resp.text().await.unwrap(), "a");
+
+    shutdown.cancel();
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap();
+    let _ = fs::remove_dir_all(&buckets_dir).await;
+}
+
+#[cfg(unix)]
+#[tokio::test]
+async fn test_listeners() {
+    use stathost::config::{ListenerConfig, ServerConfig};
+    use std::os::fd::IntoRawFd;
+    use std::os::unix::fs::PermissionsExt;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let buckets_dir = PathBuf::from(format!(
+        "/tmp/stathost-listeners-test-{}",
+        std::process::id()
+    ));
+    fs::create_dir_all(buckets_dir.join("site")).await.unwrap();
+    fs::write(
+        buckets_dir.join("site/config.toml"),
+        "[auth]\ntoken = \"tok\"\n",
+    )
+    .await
+    .unwrap();
+    fs::write(buckets_dir.join("site/a.txt"), "hello")
+        .await
+        .unwrap();
+    let socket_path = buckets_dir.with_extension("sock");
+
+    // An already listening socket, as systemd would pass it
+    let inherited = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let inherited_addr = inherited.local_addr().unwrap();
+
+    let config = ServerConfig {
+        listeners: vec![
+            ListenerConfig::Tcp {
+                address: "127.0.0.1:0".to_string(),
+                tls: false,
+            },
+            ListenerConfig::Unix {
+                path: socket_path.to_string_lossy().to_string(),
+                mode: Some(0o600),
+            },
+            ListenerConfig::Fd {
+                fd: Some(inherited.into_raw_fd()),
+                tls: false,
+            },
+        ],
+        ..Default::default()
+    };
+    let listeners = stathost::bind_listeners(&config).await.unwrap();
+    assert_eq!(listeners.len(), 3);
+    assert_eq!(
+        listeners[1].describe(),
+        format!("unix:{}", socket_path.display())
+    );
+    let tcp_addr = listeners[0].local_addr().unwrap();
+    assert_eq!(listeners[2].local_addr(), Some(inherited_addr));
+
+    let router = app(std::sync::Arc::new(stathost::BucketManager::new(
+        buckets_dir.clone(),
+    )));
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let servers: Vec<_> = listeners
+        .into_iter()
+        .map(|listener| tokio::spawn(listener.serve(router.clone(), None, shutdown.clone())))
+        .collect();
+
+    for addr in [tcp_addr, inherited_addr] {
+        let body = reqwest::get(format!("http://{}/site/a.txt", addr))
+            .await
+            .unwrap()
+            .text()
+            .await
+            .unwrap();
+        assert_eq!(body, "hello");
+    }
+
+    let mode = std::fs::metadata(&socket_path)
+        .unwrap()
+        .permissions()
+        .mode();
+    assert_eq!(mode & 0o777, 0o600);
+    let mut stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+    stream
+        .write_all(b"GET /site/a.txt HTTP/1.1\r\nHost: localhost\r\nConnection: close\r\n\r\n")
+        .await
+        .unwrap();
+    let mut response = String::new();
+    stream.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 200"));
+    assert!(response.ends_with("hello"));
+
+    // TLS listeners need [server.tls]
+    let config = ServerConfig {
+        listeners: vec![ListenerConfig::Tcp {
+            address: "127.0.0.1:0".to_string(),
+            tls: true,
+        }],
+        ..Default::default()
+    };
+    assert!(stathost::bind_listeners(&config).await.is_err());
+
+    shutdown.cancel();
+    for server in servers {
+        server.await.unwrap().unwrap();
+    }
+    let _ = fs::remove_dir_all(&buckets_dir).await;
+    let _ = fs::remove_file(&socket_path).await;
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    use std::sync::Arc;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let buckets_dir = PathBuf::from(format!("/tmp/stathost-drain-test-{}", std::process::id()));
+    fs::create_dir_all(buckets_dir.join("site")).await.unwrap();
+    fs::write(
+        buckets_dir.join("site/config.toml"),
+        "[auth]\ntoken = \"tok\"\n",
+    )
+    .await
+    .unwrap();
+
+    let config = stathost::config::ServerConfig {
+        listeners: vec![stathost::config::ListenerConfig::Tcp {
+            address: "127.0.0.1:0".to_string(),
+            tls: false,
+        }],
+        ..Default::default()
+    };
+    let listener = stathost::bind_listeners(&config).await.unwrap().remove(0);
+    let addr = listener.local_addr().unwrap();
+    let manager = Arc::new(stathost::BucketManager::new(buckets_dir.clone()));
+    let shutdown = tokio_util::sync::CancellationToken::new();
+    let server = tokio::spawn(listener.serve(app(manager.clone()), None, shutdown.clone()));
+
+    // An upload that is still sending its body
+    let mut upload = tokio::net::TcpStream::connect(addr).await.unwrap();
+    upload
+        .write_all(
+            b"PUT /site/big.bin HTTP/1.1\r\nHost: localhost\r\n\
+              Authorization: Bearer tok\r\nContent-Length: 10\r\n\
+              Connection: close\r\n\r\nhello",
+        )
+        .await
+        .unwrap();
+    for _ in 0..50 {
+        if !manager.drain().in_flight().is_empty() {
+            break;
+        }
+        sleep(Duration::from_millis(10)).await;
+    }
+    assert_eq!(manager.drain().in_flight(), vec!["site/big.bin"]);
+
+    // Draining refuses new uploads but still serves reads
+    manager.drain().start();
+    let client = reqwest::Client::new();
+    let resp = client
+        .put(format!("http://{}/site/other.txt", addr))
+        .header("Authorization", "Bearer tok")
+        .body("x")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 503);
+    assert_eq!(resp.headers()["retry-after"], "5");
+
+    // The server waits for the in-flight upload before stopping
+    shutdown.cancel();
+    sleep(Duration::from_millis(200)).await;
+    assert!(!server.is_finished());
+
+    upload.write_all(b"world").await.unwrap();
+    let mut response = String::new();
+    upload.read_to_string(&mut response).await.unwrap();
+    assert!(response.starts_with("HTTP/1.1 201"), "{}", response);
+
+    tokio::time::timeout(Duration::from_secs(5), server)
+        .await
+        .unwrap()
+        .unwrap()
+        .unwrap();
+    assert!(manager.drain().in_flight().is_empty());
+    assert_eq!(
+        fs::read_to_string(buckets_dir.join("site/big.bin"))
+            .await
+            .unwrap(),
+        "helloworld"
+    );
+
+    let _ = fs::remove_dir_all(&buckets_dir).await;
+}
+
+#[tokio::test]
+async fn test_metrics() {
+    let server =
+        TestServer::start_with(|manager| manager.with_metrics_token("admin".to_string())).await;
+    let client = reqwest::Client::new();
+
+    server.create_bucket("site", "tok").await;
+
+    let resp = client
+        .put(server.url("/site/hello.txt"))
+        .header("Authorization", "Bearer tok")
+        .body("hello")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+
+    let resp = client
+        .get(server.url("/site/hello.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.text().await.unwrap(), "hello");
+
+    let resp = client
+        .get(server.url("/site/missing.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    // Unknown buckets are not given their own label
+    let resp = client.get(server.url("/probe-123/x")).send().await.unwrap();
+    assert_eq!(resp.status(), 404);
+
+    let resp = client.get(server.url("/_metrics")).send().await.unwrap();
+    assert_eq!(resp.status(), 401);
+
+    let resp = client
+        .get(server.url("/_metrics"))
+        .header("Authorization", "Bearer wrong")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    let resp = client
+        .get(server.url("/_metrics"))
+        .header("Authorization", "Bearer admin")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert!(
+        resp.headers()["content-type"]
+            .to_str()
+            .unwrap()
+            .starts_with("text/plain; version=0.0.4")
+    );
+    let body = resp.text().await.unwrap();
+
+    assert!(
+        body.contains(
+            "stathost_http_requests_total{bucket=\"site\",method=\"PUT\",status=\"201\"} 1"
+        )
+    );
+    assert!(
+        body.contains(
+            "stathost_http_requests_total{bucket=\"site\",method=\"GET\",status=\"200\"} 1"
+        )
+    );
+    assert!(
+        body.contains(
+            "stathost_http_requests_total{bucket=\"site\",method=\"GET\",status=\"404\"} 1"
+        )
+    );
+    assert!(
+        body.contains("stathost_http_requests_total{bucket=\"\",method=\"GET\",status=\"404\"} 1")
+    );
+    assert!(!body.contains("probe-123"));
+    assert!(body.contains(
+        "stathost_http_request_duration_seconds_count{bucket=\"site\",method=\"GET\",status=\"200\"} 1"
+    ));
+    assert!(body.contains(
+        "stathost_http_request_duration_seconds_bucket{bucket=\"site\",method=\"GET\",status=\"200\",le=\"+Inf\"} 1"
+    ));
+    assert!(body.contains("stathost_bytes_uploaded_total{bucket=\"site\"} 5"));
+    assert!(body.contains("stathost_uploads_in_flight 0"));
+    assert!(body.contains("stathost_temp_files_cleaned_total 0"));
+    // config.toml is not part of the bucket contents
+    assert!(body.contains("stathost_bucket_disk_usage_bytes{bucket=\"site\"} 5"));
+
+    let _ = fs::remove_dir_all(&server.buckets_dir).await;
+}
+
+#[tokio::test]
+async fn test_request_id() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    server.create_bucket("site", "tok").await;
+
+    // Generated when the client sends none, also on errors
+    let resp = client
+        .put(server.url("/site/a.txt"))
+        .header("Authorization", "Bearer wrong")
+        .body("a")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+    let first = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert!(!first.is_empty());
+
+    let resp = client.get(server.url("/site/a.txt")).send().await.unwrap();
+    let second = resp.headers()["x-request-id"].to_str().unwrap().to_string();
+    assert_ne!(first, second);
+
+    // Propagated from an upstream proxy
+    let resp = client
+        .get(server.url("/site/a.txt"))
+        .header("X-Request-Id", "edge-1234_abcd")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.headers()["x-request-id"], "edge-1234_abcd");
+
+    // Replaced when it does not look like an ID
+    for bad in ["has space", "quote\"", &"x".repeat(200)] {
+        let resp = client
+            .get(server.url("/site/a.txt"))
+            .header("X-Request-Id", bad)
+            .send()
+            .await
+            .unwrap();
+        let id = resp.headers()["x-request-id"].to_str().unwrap();
+        assert_ne!(id, bad);
+        assert!(!id.is_empty());
+    }
+
+    let _ = fs::remove_dir_all(&server.buckets_dir).await;
+}
+
+#[tokio::test]
+async fn test_scoped_tokens() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    let bucket_path = server.buckets_dir.join("site");
+    fs::create_dir_all(&bucket_path).await.unwrap();
+    fs::write(
+        bucket_path.join("config.toml"),
+        r#"
+[[auth.tokens]]
+name = "ci"
+token = "ci-token"
+scopes = ["write"]
+paths = ["releases/"]
+
+[[auth.tokens]]
+name = "monitor"
+token = "monitor-token"
+scopes = ["list"]
+
+[[auth.tokens]]
+name = "cleanup"
+token = "cleanup-token"
+scopes = ["list", "delete"]
+paths = ["releases"]
+
+[[auth.tokens]]
+name = "human"
+token = "admin-token"
+scopes = ["admin"]
+"#,
+    )
+    .await
+    .unwrap();
+
+    let put = |path: &str, token: &str| {
+        client
+            .put(server.url(&format!("/site/{}", path)))
+            .header("Authorization", format!("Bearer {}", token))
+            .body("data")
+            .send()
+    };
+
+    // Write scope, limited to releases/
+    assert_eq!(
+        put("releases/v1.zip", "ci-token").await.unwrap().status(),
+        201
+    );
+    assert_eq!(put("index.html", "ci-token").await.unwrap().status(), 403);
+    assert_eq!(
+        put("releases-old/x", "ci-token").await.unwrap().status(),
+        403
+    );
+    assert_eq!(
+        put("releases/../index.html", "ci-token")
+            .await
+            .unwrap()
+            .status(),
+        403
+    );
+    assert_eq!(
+        put("index.html", "monitor-token").await.unwrap().status(),
+        403
+    );
+    assert_eq!(
+        put("index.html", "admin-token").await.unwrap().status(),
+        201
+    );
+    assert_eq!(put("index.html", "unknown").await.unwrap().status(), 403);
+
+    let list = |token: &str| {
+        client
+            .get(server.url("/site/_meta/list"))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+    };
+    assert_eq!(list("ci-token").await.unwrap().status(), 403);
+    let files: Vec<String> = list("monitor-token").await.unwrap().json().await.unwrap();
+    assert_eq!(files.len(), 2);
+    // Listings are limited to the token's paths
+    let files: Vec<String> = list("cleanup-token").await.unwrap().json().await.unwrap();
+    assert_eq!(files, vec!["releases/v1.zip"]);
+
+    let delete = |path: &str, token: &str| {
+        client
+            .delete(server.url(&format!("/site/{}", path)))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+    };
+    assert_eq!(
+        delete("releases/v1.zip", "ci-token")
+            .await
+            .unwrap()
+            .status(),
+        403
+    );
+    assert_eq!(
+        delete("index.html", "cleanup-token")
+            .await
+            .unwrap()
+            .status(),
+        403
+    );
+    assert_eq!(
+        delete("releases/v1.zip", "cleanup-token")
+            .await
+            .unwrap()
+            .status(),
+        204
+    );
+    assert_eq!(
+        delete("index.html", "admin-token").await.unwrap().status(),
+        204
+    );
+
+    // Files stay publicly readable
+    put("releases/v2.zip", "ci-token").await.unwrap();
+    let resp = client
+        .get(server.url("/site/releases/v2.zip"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let _ = fs::remove_dir_all(&server.buckets_dir).await;
+}
+
+#[tokio::test]
+async fn test_hashed_tokens() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    let argon2 = stathost::hash_token("argon-secret", false).unwrap();
+    assert!(argon2.starts_with("$argon2id$"));
+    let sha256 = stathost::hash_token("sha-secret", true).unwrap();
+    assert_eq!(
+        sha256,
+        "sha256:4e3acc0eb2976bde9f51c7b6332af01535e3d863474acac276e1fcd60224c738"
+    );
+
+    let bucket_path = server.buckets_dir.join("site");
+    fs::create_dir_all(&bucket_path).await.unwrap();
+    fs::write(
+        bucket_path.join("config.toml"),
+        format!(
+            r#"
+[auth]
+token_hash = "{}"
+
+[[auth.tokens]]
+name = "ci"
+token_hash = "{}"
+scopes = ["write"]
+"#,
+            argon2, sha256
+        ),
+    )
+    .await
+    .unwrap();
+
+    let put = |token: &str| {
+        client
+            .put(server.url("/site/a.txt"))
+            .header("Authorization", format!("Bearer {}", token))
+            .body("a")
+            .send()
+    };
+    assert_eq!(put("argon-secret").await.unwrap().status(), 201);
+    // Served from the verification cache the second time
+    assert_eq!(put("argon-secret").await.unwrap().status(), 201);
+    assert_eq!(put("sha-secret").await.unwrap().status(), 201);
+    assert_eq!(put("wrong").await.unwrap().status(), 403);
+    // The hash itself is not a token
+    assert_eq!(put(&sha256).await.unwrap().status(), 403);
+
+    // A bucket with a malformed hash fails to load
+    let broken = server.buckets_dir.join("broken");
+    fs::create_dir_all(&broken).await.unwrap();
+    fs::write(
+        broken.join("config.toml"),
+        "[auth]\ntoken_hash = \"sha256:abc\"\n",
+    )
+    .await
+    .unwrap();
+    let resp = client
+        .put(server.url("/broken/a.txt"))
+        .header("Authorization", "Bearer abc")
+        .body("a")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    let _ = fs::remove_dir_all(&server.buckets_dir).await;
+}
+
+#[tokio::test]
+async fn test_private_bucket() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    let bucket_path = server.buckets_dir.join("artifacts");
+    fs::create_dir_all(&bucket_path).await.unwrap();
+    fs::write(
+        bucket_path.join("config.toml"),
+        r#"visibility = "private"
+
+[auth]
+token = "admin"
+
+[[auth.tokens]]
+name = "reader"
+token = "read-token"
+scopes = ["read"]
+
+[[auth.tokens]]
+name = "ci"
+token = "ci-token"
+scopes = ["write"]
+"#,
+    )
+    .await
+    .unwrap();
+    fs::write(bucket_path.join("build.bin"), "0123456789")
+        .await
+        .unwrap();
+    fs::write(bucket_path.join("index.html"), "<h1>artifacts</h1>")
+        .await
+        .unwrap();
+
+    let resp = client
+        .get(server.url("/artifacts/build.bin"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    let challenges: Vec<_> = resp
+        .headers()
+        .get_all("www-authenticate")
+        .iter()
+        .map(|v| v.to_str().unwrap().to_string())
+        .collect();
+    assert_eq!(
+        challenges,
+        vec![
+            "Basic realm=\"artifacts\", charset=\"UTF-8\"",
+            "Bearer realm=\"artifacts\""
+        ]
+    );
+
+    for path in ["/artifacts/", "/artifacts"] {
+        let resp = client.get(server.url(path)).send().await.unwrap();
+        assert_eq!(resp.status(), 401);
+    }
+    let resp = client
+        .head(server.url("/artifacts/build.bin"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+
+    // Bearer and Basic (any user name, token as password)
+    let resp = client
+        .get(server.url("/artifacts/build.bin"))
+        .bearer_auth("read-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "0123456789");
+
+    let resp = client
+        .get(server.url("/artifacts/build.bin"))
+        .basic_auth("anyone", Some("read-token"))
+        .header("Range", "bytes=2-4")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 206);
+    assert_eq!(resp.text().await.unwrap(), "234");
+
+    let resp = client
+        .get(server.url("/artifacts/"))
+        .basic_auth("admin", Some("admin"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    // Wrong credentials ask again, a token without read scope is refused
+    let resp = client
+        .get(server.url("/artifacts/build.bin"))
+        .basic_auth("anyone", Some("wrong"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 401);
+    let resp = client
+        .get(server.url("/artifacts/build.bin"))
+        .bearer_auth("ci-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    // Other buckets stay public
+    server.create_bucket("public", "tok").await;
+    fs::write(server.buckets_dir.join("public/a.txt"), "a")
+        .await
+        .unwrap();
+    let resp = client
+        .get(server.url("/public/a.txt"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+
+    let _ = fs::remove_dir_all(&server.buckets_dir).await;
+}
+
+#[tokio::test]
+async fn test_presigned_urls() {
+    let server = TestServer::start().await;
+    let client = reqwest::Client::new();
+
+    let bucket_path = server.buckets_dir.join("partners");
+    fs::create_dir_all(bucket_path.join("reports"))
+        .await
+        .unwrap();
+    fs::write(
+        bucket_path.join("config.toml"),
+        r#"visibility = "private"
+
+[auth]
+token = "admin"
+
+[[auth.tokens]]
+name = "reports"
+token = "reports-token"
+scopes = ["read"]
+paths = ["reports"]
+
+[presign]
+secret = "0123456789abcdef0123456789abcdef"
+max_expires = 86400
+"#,
+    )
+    .await
+    .unwrap();
+    fs::write(bucket_path.join("reports/q3 final.pdf"), "report")
+        .await
+        .unwrap();
+    fs::write(bucket_path.join("secret.txt"), "secret")
+        .await
+        .unwrap();
+
+    let presign = |token: &str, body: serde_json::Value| {
+        client
+            .post(server.url("/partners/_meta/presign"))
+            .bearer_auth(token)
+            .json(&body)
+            .send()
+    };
+
+    let resp = presign(
+        "reports-token",
+        serde_json::json!({"path": "reports/q3 final.pdf", "method": "GET", "expires_in": 86400}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 200);
+    let json: serde_json::Value = resp.json().await.unwrap();
+    let url = json["url"].as_str().unwrap().to_string();
+    assert!(url.starts_with("/partners/reports/q3%20final.pdf?expires="));
+    assert_eq!(json["method"], "GET");
+
+    let resp = client.get(server.url(&url)).send().await.unwrap();
+    assert_eq!(resp.status(), 200);
+    assert_eq!(resp.text().await.unwrap(), "report");
+
+    // Tampered signature, other path or expired URLs are refused
+    let tampered = format!("{}0", url.trim_end_matches(|c: char| c.is_ascii_hexdigit()));
+    let resp = client.get(server.url(&tampered)).send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+    let query = url.split_once('?').unwrap().1;
+    let resp = client
+        .get(server.url(&format!("/partners/secret.txt?{}", query)))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+    let expired = url.replace(&format!("expires={}", json["expires"]), "expires=1");
+    let resp = client.get(server.url(&expired)).send().await.unwrap();
+    assert_eq!(resp.status(), 403);
+
+    // A GET URL cannot be used to upload
+    let resp = client
+        .put(server.url(&url))
+        .body("overwritten")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    // Tokens can only presign what they may do themselves
+    let resp = presign(
+        "reports-token",
+        serde_json::json!({"path": "secret.txt", "method": "GET"}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 403);
+    let resp = presign(
+        "reports-token",
+        serde_json::json!({"path": "reports/upload.bin", "method": "PUT"}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 403);
+    let resp = presign(
+        "admin",
+        serde_json::json!({"path": "reports/q3 final.pdf", "method": "GET", "expires_in": 86401}),
+    )
+    .await
+    .unwrap();
+    assert_eq!(resp.status(), 400);
+
+    // Presigned upload without the bucket token
+    let resp = presign(
+        "admin",
+        serde_json::json!({"path": "inbox/data.csv", "method": "PUT"}),
+    )
+    .await
+    .unwrap();
+    let json: serde_json::Value = resp.json().await.unwrap();
+    let upload_url = json["url"].as_str().unwrap();
+    let resp = client
+        .put(server.url(upload_url))
+        .body("a,b,c")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 201);
+    assert_eq!(
+        fs::read_to_string(bucket_path.join("inbox/data.csv"))
+            .await
+            .unwrap(),
+        "a,b,c"
+    );
+
+    // Buckets without [presign] do not offer it
+    server.create_bucket("plain", "tok").await;
+    let resp = client
+        .post(server.url("/plain/_meta/presign"))
+        .bearer_auth("tok")
+        .json(&serde_json::json!({"path": "a.txt", "method": "GET"}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 404);
+
+    let _ = fs::remove_dir_all(&server.buckets_dir).await;
+}
+
+#[tokio::test]
+async fn test_jwt_tokens() {
+    use jsonwebtoken::{Algorithm, EncodingKey, Header};
+
+    let secret = "jwt-test-signing-key-0123456789";
+    let jwks_path = PathBuf::from(format!("/tmp/stathost-jwks-{}.json", std::process::id()));
+    let jwks = serde_json::json!({
+        "keys": [{
+            "kty": "oct",
+            "kid": "test",
+            "alg": "HS256",
+            "k": base64::Engine::encode(
+                &base64::prelude::BASE64_URL_SAFE_NO_PAD,
+                secret,
+            ),
+        }]
+    });
+    fs::write(&jwks_path, jwks.to_string()).await.unwrap();
+
+    let verifier = stathost::JwtVerifier::load(&stathost::config::JwtConfig {
+        jwks: Some(jwks_path.to_string_lossy().into_owned()),
+        pem: None,
+        algorithm: Algorithm::RS256,
+        issuer: Some("https://auth.example".to_string()),
+        audience: Some("stathost".to_string()),
+        claim: "scope".to_string(),
+        leeway: 0,
+    })
+    .unwrap();
+    let server = TestServer::start_with(|manager| manager.with_jwt(verifier)).await;
+    let client = reqwest::Client::new();
+    server.create_bucket("site", "static-token").await;
+    server.create_bucket("other", "other-token").await;
+
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let claims = serde_json::json!({
+        "sub": "deploy-bot",
+        "iss": "https://auth.example",
+        "aud": "stathost",
+        "exp": now + 300,
+        "scope": "openid stathost:site:write stathost:*:list",
+    });
+    let sign = |claims: &serde_json::Value, key: &str| {
+        let mut header = Header::new(Algorithm::HS256);
+        header.kid = Some("test".to_string());
+        jsonwebtoken::encode(&header, claims, &EncodingKey::from_secret(key.as_bytes())).unwrap()
+    };
+    let put = |bucket: &str, token: String| {
+        client
+            .put(server.url(&format!("/{}/a.txt", bucket)))
+            .header("Authorization", format!("Bearer {}", token))
+            .body("a")
+            .send()
+    };
+
+    let token = sign(&claims, secret);
+    assert_eq!(put("site", token.clone()).await.unwrap().status(), 201);
+    // Write was only granted on `site`
+    assert_eq!(put("other", token.clone()).await.unwrap().status(), 403);
+    let resp = client
+        .get(server.url("/other/_meta/list"))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 200);
+    // No delete scope
+    let resp = client
+        .delete(server.url("/site/a.txt"))
+        .header("Authorization", format!("Bearer {}", token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+
+    // Static tokens keep working alongside
+    assert_eq!(
+        put("site", "static-token".to_string())
+            .await
+            .unwrap()
+            .status(),
+        201
+    );
+
+    // Wrong key, expired, wrong audience, wrong issuer, missing expiry
+    assert_eq!(
+        put("site", sign(&claims, "some-other-signing-key-0123456"))
+            .await
+            .unwrap()
+            .status(),
+        403
+    );
+    let mut rejected = Vec::new();
+    for (field, value) in [
+        ("exp", serde_json::json!(now - 10)),
+        ("aud", serde_json::json!("elsewhere")),
+        ("iss", serde_json::json!("https://evil.example")),
+    ] {
+        let mut claims = claims.clone();
+        claims[field] = value;
+        rejected.push(sign(&claims, secret));
+    }
+    let mut claims_without_exp = claims.clone();
+    claims_without_exp.as_object_mut().unwrap().remove("exp");
+    rejected.push(sign(&claims_without_exp, secret));
+    for token in rejected {
+        assert_eq!(put("site", token).await.unwrap().status(), 403);
+    }
+
+    // `alg: none` is never accepted
+    let unsigned = format!(
+        "{}.{}.",
+        base64::Engine::encode(
+            &base64::prelude::BASE64_URL_SAFE_NO_PAD,
+            r#"{"alg":"none","kid":"test"}"#
+        ),
+        base64::Engine::encode(&base64::prelude::BASE64_URL_SAFE_NO_PAD, claims.to_string())
+    );
+    assert_eq!(put("site", unsigned).await.unwrap().status(), 403);
+
+    let _ = fs::remove_file(&jwks_path).await;
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_ip_filter() {
+    let proxies = stathost::TrustedProxies::compile(&["127.0.0.1".to_string()]).unwrap();
+    let server_rules: stathost::config::IpAccessConfig = toml::from_str(
+        "[read]\ndeny = [\"198.51.100.9\"]\n\n[write]\ndeny = [\"198.51.100.0/24\"]\n",
+    )
+    .unwrap();
+    let access = stathost::IpAccess::compile(&server_rules).unwrap();
+    let server = TestServer::start_with(|manager| {
+        manager.with_trusted_proxies(proxies).with_ip_access(access)
+    })
+    .await;
+    let client = reqwest::Client::new();
+    server
+        .create_bucket_with_config(
+            "office",
+            "tok",
+            "[ip.write]\nallow = [\"203.0.113.0/24\", \"2001:db8::/32\"]\n\n[ip.read]\ndeny = [\"192.0.2.66\"]\n",
+        )
+        .await;
+    server.create_bucket("open", "tok").await;
+
+    let put = |bucket: &str, header: Option<(&'static str, &str)>| {
+        let mut request = client
+            .put(server.url(&format!("/{}/a.txt", bucket)))
+            .header("Authorization", "Bearer tok")
+            .body("a");
+        if let Some((name, value)) = header {
+            request = request.header(name, value.to_string());
+        }
+        request.send()
+    };
+    let status = |resp: reqwest::Response| resp.status().as_u16();
+
+    let xff = |value| Some(("X-Forwarded-For", value));
+    assert_eq!(
+        status(put("office", xff("203.0.113.5")).await.unwrap()),
+        201
+    );
+    assert_eq!(status(put("office", xff("192.0.2.1")).await.unwrap()), 403);
+    // Only the hops added by trusted proxies count, not what the client sent
+    assert_eq!(
+        status(put("office", xff("203.0.113.5, 192.0.2.1")).await.unwrap()),
+        403
+    );
+    assert_eq!(
+        status(put("office", xff("192.0.2.1, 203.0.113.5")).await.unwrap()),
+        201
+    );
+    assert_eq!(status(put("office", xff("unknown")).await.unwrap()), 403);
+    // The trusted proxy itself is not in the allow list
+    assert_eq!(status(put("office", None).await.unwrap()), 403);
+    let forwarded = Some(("Forwarded", "for=\"[2001:db8::1]:4711\";proto=https"));
+    assert_eq!(status(put("office", forwarded).await.unwrap()), 201);
+
+    // Reads have their own lists
+    let get = |ip: &str| {
+        client
+            .get(server.url("/office/a.txt"))
+            .header("X-Forwarded-For", ip.to_string())
+            .send()
+    };
+    assert_eq!(status(get("192.0.2.1").await.unwrap()), 200);
+    assert_eq!(status(get("192.0.2.66").await.unwrap()), 403);
+
+    // An encoded bucket segment is the same bucket to the lists
+    let resp = client
+        .get(server.url("/%6Fffice/a.txt"))
+        .header("X-Forwarded-For", "192.0.2.66")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(status(resp), 403);
+    let resp = client
+        .put(server.url("/%6F%66fice/a.txt"))
+        .header("Authorization", "Bearer tok")
+        .header("X-Forwarded-For", "192.0.2.1")
+        .body("b")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(status(resp), 403);
+    assert_eq!(status(get("192.0.2.1").await.unwrap()), 200);
+
+    // Server-wide lists apply to every bucket
+    assert_eq!(status(put("open", xff("192.0.2.1")).await.unwrap()), 201);
+    assert_eq!(status(put("open", xff("198.51.100.7")).await.unwrap()), 403);
+    assert_eq!(
+        status(put("office", xff("198.51.100.7")).await.unwrap()),
+        403
+    );
+
+    // Including the metrics endpoint
+    let metrics = |ip: &str| {
+        client
+            .get(server.url("/_metrics"))
+            .header("X-Forwarded-For", ip.to_string())
+            .send()
+    };
+    assert_eq!(status(metrics("192.0.2.1").await.unwrap()), 200);
+    assert_eq!(status(metrics("198.51.100.9").await.unwrap()), 403);
+
+    // Invalid ranges fail the bucket's config
+    server
+        .create_bucket_with_config("broken", "tok", "[ip.write]\nallow = [\"10.0.0.0/33\"]\n")
+        .await;
+    assert_eq!(status(put("broken", None).await.unwrap()), 404);
+    server.cleanup().await;
+
+    // Without trusted proxies forwarding headers are ignored
+    let server = TestServer::start().await;
+    server
+        .create_bucket_with_config(
+            "office",
+            "tok",
+            "[ip.write]\nallow = [\"203.0.113.0/24\"]\n",
+        )
+        .await;
+    let resp = client
+        .put(server.url("/office/a.txt"))
+        .header("Authorization", "Bearer tok")
+        .header("X-Forwarded-For", "203.0.113.5")
+        .body("a")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), 403);
+    server.cleanup().await;
+}
+
+#[tokio::test]
+async fn test_rate_limits() {
+    let limits: stathost::config::RateLimitConfig = toml::from_str(
+        r#"
+ip = { rate = 0.01, burst = 5 }
+
+[auth_failures]
+ip = { rate = 0.01, burst = 2 }
+lockout = 60
+"#,
+    )
+    .unwrap();
+    let limiter = stathost::RateLimiter::new(&limits).unwrap().unwrap();
+    let proxies = stathost::TrustedProxies::compile(&["127.0.0.1".to_string()]).unwrap();
+    let server = TestServer::start_with(|manager| {
+        manager
+            .with_trusted_proxies(proxies)
+            .with_rate_limiter(limiter)
+    })
+    .await;
+    let client = reqwest::Client::new();
+    server.create_bucket("site", "tok").await;
+    fs::write(server.buckets_dir.join("site/a.txt"), "a")
+        .await
+        .unwrap();
+
+    let get = |ip: &'static str| {
+        client
+            .get(server.url("/site/a.txt"))
+            .header("X-Forwarded-For", ip)
+            .send()
+    };
+    let put = |ip: &'static str, token: &'static str| {
+        client
+            .put(server.url("/site/b.txt"))
+            .header("X-Forwarded-For", ip)
+            .header("Authorization", format!("Bearer {}", token))
+            .body("b")
+            .send()
+    };
+
+    // Requests per client address
+    for _ in 0..5 {
+        assert_eq!(get("192.0.2.1").await.unwrap().status(), 200);
+    }
+    let resp = get("192.0.2.1").await.unwrap();
+    assert_eq!(resp.status(), 429);
+    let retry_after: u64 = resp.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after >= 1);
+    // Other clients are unaffected
+    assert_eq!(get("192.0.2.2").await.unwrap().status(), 200);
+
+    // Failed token checks lead to a lockout
+    assert_eq!(put("192.0.2.3", "wrong").await.unwrap().status(), 403);
+    assert_eq!(put("192.0.2.3", "wrong").await.unwrap().status(), 403);
+    assert_eq!(put("192.0.2.3", "wrong").await.unwrap().status(), 403);
+    let resp = put("192.0.2.3", "tok").await.unwrap();
+    assert_eq!(resp.status(), 429);
+    let retry_after: u64 = resp.headers()["retry-after"]
+        .to_str()
+        .unwrap()
+        .parse()
+        .unwrap();
+    assert!(retry_after > 50 && retry_after <= 60);
+    // Requests without credentials still get through
+    assert_eq!(get("192.0.2.3").await.unwrap().status(), 200);
+    // Missing tokens are not counted as guesses
+    for _ in 0..3 {
+        let resp = client
+            .put(server.url("/site/b.txt"))
+            .header("X-Forwarded-For", "192.0.2.4")
+            .body("b")
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), 401);
+    }
+    assert_eq!(put("192.0.2.4", "tok").await.unwrap().status(), 201);
+    server.cleanup().await;
+
+    // Requests per bucket
+    let limits: stathost::config::RateLimitConfig =
+        toml::from_str("bucket = { rate = 0.01, burst = 2 }").unwrap();
+    let limiter = stathost::RateLimiter::new(&limits).unwrap().unwrap();
+    let server = TestServer::start_with(|manager| manager.with_rate_limiter(limiter)).await;
+    server.create_bucket("site", "tok").await;
+    server.create_bucket("other", "tok").await;
+    let list = |bucket: &str| {
+        client
+            .get(server.url(&format!("/{}/_meta/list", bucket)))
+            .header("Authorization", "Bearer tok")
+            .send()
+    };
+    assert_eq!(list("site").await.unwrap().status(), 200);
+    assert_eq!(list("site").await.unwrap().status(), 200);
+    assert_eq!(list("site").await.unwrap().status(), 429);
+    assert_eq!(list("other").await.unwrap().status(), 200);
+
+    server.cleanup().await;
+
+    // Per-bucket lockouts, with the bucket segment percent-encoded
+    let limits: stathost::config::RateLimitConfig =
+        toml::from_str("[auth_failures]\nbucket = { rate = 0.01, burst = 2 }\nlockout = 60\n")
+            .unwrap();
+    let limiter = stathost::RateLimiter::new(&limits).unwrap().unwrap();
+    let server = TestServer::start_with(|manager| manager.with_rate_limiter(limiter)).await;
+    server.create_bucket("site", "tok").await;
+    let put = |path: &str, token: &str| {
+        client
+            .put(server.url(path))
+            .header("Authorization", format!("Bearer {}", token))
+            .body("b")
+            .send()
+    };
+    // Guesses through encoded paths are counted against the bucket
+    assert_eq!(put("/%73ite/a.txt", "wrong").await.unwrap().status(), 403);
+    assert_eq!(put("/%73%69te/a.txt", "wrong").await.unwrap().status(), 403);
+    assert_eq!(put("/site/a.txt", "wrong").await.unwrap().status(), 403);
+    for path in ["/site/a.txt", "/%73ite/a.txt", "/%73%69%74%65/a.txt"] {
+        assert_eq!(put(path, "wrong").await.unwrap().status(), 429);
+        assert_eq!(put(path, "tok").await.unwrap().status(), 429);
+    }
+
+    server.cleanup().await;
+
+    // Wrong metrics tokens are guesses too
+    let limits: stathost::config::RateLimitConfig =
+        toml::from_str("[auth_failures]\nip = { rate = 0.01, burst = 2 }\nlockout = 60\n").unwrap();
+    let limiter = stathost::RateLimiter::new(&limits).unwrap().unwrap();
+    let server = TestServer::start_with(|manager| {
+        manager
+            .with_rate_limiter(limiter)
+            .with_metrics_token("admin".to_string())
+    })
+    .await;
+    let scrape = |token: &str| {
+        client
+            .get(server.url("/_metrics"))
+            .header("Authorization", format!("Bearer {}", token))
+            .send()
+    };
+    for _ in 0..3 {
+        assert_eq!(scrape("wrong").await.unwrap().status(), 403);
+    }
+    assert_eq!(scrape("wrong").await.unwrap().status(), 429);
+    assert_eq!(scrape("admin").await.unwrap().status(), 429);
+
+    // Nonsensical limits are rejected
+    let limits: stathost::config::RateLimitConfig =
+        toml::from_str("ip = { rate = 0.0, burst = 5 }").unwrap();
+    assert!(stathost::RateLimiter::new(&limits).is_err());
+    assert!(
+        stathost::RateLimiter::new(&stathost::config::RateLimitConfig::default())
+            .unwrap()
+            .is_none()
+    );
+    server.cleanup().await;
+}